@@ -1,11 +1,10 @@
-extern crate nix;
 extern crate mio;
 extern crate fdringbuf;
 use fdringbuf::fdbuf;
+use std::time::Duration;
 
 #[derive(Copy, Clone, Debug)]
 enum Protocol {
-    Error,
     Hello(i32),
     Data(u8),
     Goodbye,
@@ -16,58 +15,57 @@ fn send_data<U>(i: i32, mut s: fdbuf::Sender<Protocol, U>) {
     use std::thread;
 
     s.send_foreach(1, |_| Protocol::Hello(i)).unwrap();
-    thread::sleep_ms(100);
+    thread::sleep(Duration::from_millis(100));
     s.send_foreach(6, |j| Protocol::Data(j as u8)).unwrap();
 
-    thread::sleep_ms(100);
+    thread::sleep(Duration::from_millis(100));
     s.send_foreach(1, |_| Protocol::Goodbye).unwrap();
 }
 
-struct MyReceiver(Vec<fdbuf::Receiver<Protocol, Vec<u8>>>, Vec<mio::Io>, i32);
-
-impl mio::Handler for MyReceiver {
-    type Timeout = ();
-    type Message = ();
-
-    fn ready(&mut self, eloop: &mut mio::EventLoop<MyReceiver>, token: mio::Token, _: mio::EventSet) {
-        let mut goodbye = false;
-        {
-            let r = &mut self.0[token.0];
-            r.recv(|d| {
-                for dd in d {
-                    println!("Receiving {:?} from thread {}", dd, token.0);
-                    if let &Protocol::Goodbye = dd { goodbye = true; }
-                }
-                (d.len(), false)
-            }).unwrap();
-        }
-        if goodbye {
-            self.2 += 1;
-            if self.2 == self.0.len() as i32 { eloop.shutdown() }
-        }
-    }    
-}
-
 fn main() {
-    let mut eloop = mio::EventLoop::new().unwrap();
+    let mut poll = mio::Poll::new().unwrap();
+    let mut events = mio::Events::with_capacity(8);
 
-    let mut rvec = MyReceiver(vec![], vec![], 0);
+    let mut receivers = vec![];
     for i in 0..8 {
-        use self::nix::sys::eventfd::*;
-        let (efd1, efd2) = (eventfd(0, EFD_CLOEXEC).unwrap(), eventfd(0, EFD_CLOEXEC).unwrap());
-        let pipe1 = fdbuf::Pipe { reader: efd1, writer: efd1 };
-        let pipe2 = fdbuf::Pipe { reader: efd2, writer: efd2 };
         let buf = vec![0u8; fdringbuf::channel_bufsize::<Protocol>(64)];
-
-        let (s, r) = fdbuf::channel(buf, pipe1, pipe2);
+        let (s, mut r) = fdbuf::channel_new(buf).unwrap();
         std::thread::spawn(move || { send_data(i, s) });
-        rvec.0.push(r);
-        for fd in [efd1, efd2].into_iter() {
-            let mioio = mio::Io::from_raw_fd(*fd);
-            eloop.register(&mioio, mio::Token(i as usize)).unwrap();
-            rvec.1.push(mioio);
-        }
+        poll.registry().register(&mut r, mio::Token(i as usize), mio::Interest::READABLE).unwrap();
+        receivers.push(Some(r));
     }
 
-    eloop.run(&mut rvec).unwrap();
+    // Nothing gets signalled until we have told the senders that we're waiting.
+    let mut ready: Vec<_> = (0..receivers.len()).collect();
+    let mut goodbyes = 0;
+    while goodbyes < receivers.len() {
+        if ready.is_empty() {
+            poll.poll(&mut events, None).unwrap();
+            ready.extend(events.iter().map(|e| e.token().0));
+        }
+        for i in ready.drain(..) {
+            let mut goodbye = false;
+            {
+                let r = match receivers[i] { Some(ref mut r) => r, None => continue };
+                while r.wait_ready().unwrap() > 0 {
+                    r.recv(|d| {
+                        for dd in d {
+                            match *dd {
+                                Protocol::Hello(j) => println!("Thread {} says hello as {}", i, j),
+                                Protocol::Data(b) => println!("Receiving {} from thread {}", b, i),
+                                Protocol::Goodbye => { println!("Thread {} says goodbye", i); goodbye = true; }
+                            }
+                        }
+                        (d.len(), false)
+                    }).unwrap();
+                    if goodbye { break; }
+                }
+            }
+            if goodbye {
+                let mut r = receivers[i].take().unwrap();
+                poll.registry().deregister(&mut r).unwrap();
+                goodbyes += 1;
+            }
+        }
+    }
 }
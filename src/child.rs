@@ -0,0 +1,39 @@
+//! For tests that need a peer in another process: runs a single test of this test binary
+//! again, in a child process, with a Unix socket connected to the parent.
+//!
+//! The child's test should be #[ignore]d, and return right away if `socket` returns None,
+//! so that it only does something when spawned.
+
+use std::io;
+use std::os::unix::io::{AsRawFd, FromRawFd};
+use std::os::unix::net::UnixStream;
+use std::os::unix::process::CommandExt;
+use std::process::{Child, Command};
+
+const SOCKET_VAR: &'static str = "FDRINGBUF_TEST_SOCKET";
+
+/// Runs the test with the given path (e g "fdbuf::tests::peer_died_child") in a child process.
+pub fn spawn(name: &str) -> (Child, UnixStream) {
+    let (a, b) = UnixStream::pair().unwrap();
+    let fd = b.as_raw_fd();
+    let mut c = Command::new(::std::env::current_exe().unwrap());
+    c.args(&[name, "--exact", "--ignored", "--nocapture", "--test-threads=1"]).env(SOCKET_VAR, fd.to_string());
+    // Let the socket survive exec. This runs between fork and exec, where only
+    // async-signal-safe calls are allowed (fcntl is).
+    unsafe { c.pre_exec(move || {
+        if ::libc::fcntl(fd, ::libc::F_SETFD, 0) == -1 { Err(io::Error::last_os_error()) } else { Ok(()) }
+    }) };
+    let child = c.spawn().unwrap();
+    (child, a)
+}
+
+/// In the child, the socket connected to the parent.
+pub fn socket() -> Option<UnixStream> {
+    let fd = match ::std::env::var(SOCKET_VAR) { Ok(fd) => fd, Err(_) => return None };
+    Some(unsafe { UnixStream::from_raw_fd(fd.parse().unwrap()) })
+}
+
+/// Waits for the child, and checks that its test passed.
+pub fn wait(mut child: Child) {
+    assert!(child.wait().unwrap().success());
+}
@@ -1,73 +1,281 @@
 //! Ringbuffer with signalling via fd:s.
-//! You can use it with std::os::Pipe, but do try nix-rust's eventfds
+//! You can use it with pipes, but do try eventfds
 //! for slightly better performance!
 //! You will typically integrate with mio so you can wait for many fds at once,
-//! hence there are no functions that actually wait, just functions that give out
-//! the Fd to wait for.
+//! hence the main functions do not wait, they just give out the Fd to wait for.
+//! For simple cases, there are also blocking versions of send and recv.
+//!
+//! The signalling is pluggable, see the `signal` module. By default, it's a `Pipe`
+//! of fds, but it can also be e g a futex, or a Condvar if you don't need any fds.
 
-use std::os::unix::io::RawFd;
+use std::os::unix::io::{RawFd, OwnedFd, AsRawFd, FromRawFd};
 use std::io;
 use std::ops::DerefMut;
+use std::sync::Arc;
+use std::task::{Context, Poll, Waker};
+use std::cell::Cell;
+use std::time::{Duration, Instant};
+use peer::{Identity, PidFd};
+use signal::{Signal, Futex};
 
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub use poller::{Poller, Endpoint};
 
-pub struct Sender<T, U> {
+#[cfg(target_os = "linux")]
+pub use server::{Server, Connection, Credentials};
+
+
+pub struct Sender<T, U, S: Signal = Pipe> {
     inner: ::ringbuf::Sender<T, U>,
-    signal_fd: RawFd,
-    wait_fd: RawFd,
+    signal: S,
+    wait: S,
+    peer: Peer,
+    sleeping: Cell<bool>,
+    strategy: WaitStrategy,
+    _fds: Option<Arc<(OwnedPipe, OwnedPipe)>>,
 }
 
-pub struct Receiver<T, U> {
+pub struct Receiver<T, U, S: Signal = Pipe> {
     inner: ::ringbuf::Receiver<T, U>,
-    signal_fd: RawFd,
-    wait_fd: RawFd,
+    signal: S,
+    wait: S,
+    peer: Peer,
+    sleeping: Cell<bool>,
+    strategy: WaitStrategy,
+    _fds: Option<Arc<(OwnedPipe, OwnedPipe)>>,
+}
+
+/// How the blocking functions wait: first spin on the buffer's item count, then
+/// yield the thread, and only then go to sleep. The default is to sleep right away.
+/// Spinning might save a context switch, at the cost of burning CPU.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub struct WaitStrategy {
+    /// Check the item count this many times...
+    pub spin: u32,
+    /// ...but stop spinning after this time.
+    pub spin_time: Option<Duration>,
+    /// Then yield the thread and check again, this many times.
+    pub yields: u32,
+}
+
+/// Which part of the WaitStrategy that found the buffer ready.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Wakeup {
+    /// No need to wait.
+    Immediate,
+    Spin,
+    Yield,
+    Sleep,
+}
+
+fn spin<F: Fn() -> bool>(strategy: &WaitStrategy, ready: F) -> Option<Wakeup> {
+    let start = strategy.spin_time.map(|_| Instant::now());
+    for _ in 0..strategy.spin {
+        if ready() { return Some(Wakeup::Spin) };
+        if let (Some(s), Some(t)) = (start, strategy.spin_time) { if s.elapsed() >= t { break } };
+        ::std::hint::spin_loop();
+    }
+    for _ in 0..strategy.yields {
+        ::std::thread::yield_now();
+        if ready() { return Some(Wakeup::Yield) };
+    }
+    None
 }
 
 /*unsafe impl<'a, T: Copy> Send for Sender<'a, T> {}
 unsafe impl<'a, T: Copy> Send for Receiver<'a, T> {}
 */
 
+/// Signals the fd. If the fd is non-blocking and full (e g a pipe), there is
+/// already a wakeup pending, so that counts as success too.
 fn write_fd(fd: RawFd) -> io::Result<()> {
-    let e = unsafe { ::libc::write(fd, &1u64 as *const _ as *const ::libc::c_void, ::std::mem::size_of::<u64>() as ::libc::size_t) };
-    trace!("write {} to fd {}", e, fd);
-    if e == -1 { return Err(io::Error::last_os_error()) }
-    assert!(e > 0);
-    Ok(())
+    loop {
+        let e = unsafe { ::libc::write(fd, &1u64 as *const _ as *const ::libc::c_void, ::std::mem::size_of::<u64>() as ::libc::size_t) };
+        trace!("write {} to fd {}", e, fd);
+        if e != -1 { return Ok(()) };
+        let err = io::Error::last_os_error();
+        match err.kind() {
+            io::ErrorKind::Interrupted => {},
+            io::ErrorKind::WouldBlock => return Ok(()),
+            _ => return Err(err),
+        }
+    }
 }
 
-fn flush_fd(fd: RawFd) -> io::Result<()> {
+/// A pidfd for the process owning the other side (none if that's this process).
+/// Once given out by peer_fd, it's only replaced by reopen_peer_fd, so that it stays open
+/// until the caller has deregistered it from its event loop.
+#[derive(Default)]
+struct Peer {
+    /// The process the pidfd was opened for.
+    id: Option<Identity>,
+    pidfd: Option<PidFd>,
+    /// Given out by peer_fd.
+    exposed: bool,
+}
+
+impl Peer {
+    /// True if the other side has been handed off to another process since the pidfd was given out.
+    fn changed(&self, id: Option<Identity>) -> bool { self.exposed && id.is_some() && id != self.id }
+
+    /// Opens a pidfd for the process, unless we already have one for it.
+    /// Fails with PeerDied if the process is already gone.
+    fn reopen(&mut self, id: Option<Identity>) -> io::Result<Option<RawFd>> {
+        if let Some(i) = id {
+            if id != self.id {
+                self.id = None;
+                self.pidfd = None;
+                if !i.is_current() { self.pidfd = Some(try!(try!(PidFd::open(i)).ok_or(::ringbuf::Error::PeerDied))) };
+                self.id = id;
+            }
+        }
+        Ok(self.pidfd.as_ref().map(|p| p.fd()))
+    }
+
+    /// Calls f with a pidfd for the current owner of the other side, or None if it's this process.
+    /// If the pidfd given out is out of date, a temporary one is used.
+    fn with_current<R, F: FnOnce(Option<&PidFd>) -> io::Result<R>>(&mut self, id: Option<Identity>, f: F) -> io::Result<R> {
+        if !self.changed(id) {
+            try!(self.reopen(id));
+            return f(self.pidfd.as_ref());
+        }
+        let id = id.unwrap();
+        if id.is_current() { return f(None) };
+        let p = try!(try!(PidFd::open(id)).ok_or(::ringbuf::Error::PeerDied));
+        f(Some(&p))
+    }
+
+    fn check(&mut self, id: Option<Identity>) -> io::Result<()> {
+        self.with_current(id, |p| match p {
+            Some(p) if try!(p.is_dead()) => Err(::ringbuf::Error::PeerDied.into()),
+            _ => Ok(()),
+        })
+    }
+
+    /// For peer_fd: the pidfd given out before, if any.
+    fn expose(&mut self, id: Option<Identity>) -> io::Result<Option<RawFd>> {
+        if !self.exposed { try!(self.reopen(id)); }
+        self.exposed = true;
+        Ok(self.pidfd.as_ref().map(|p| p.fd()))
+    }
+}
+
+/// Clears the fd. Returns false if there was nothing to read from a non-blocking fd.
+fn flush_fd(fd: RawFd) -> io::Result<bool> {
     type Arr = [u64; 32];
-    let b: Arr = unsafe { ::std::mem::uninitialized() };
-    let e = unsafe { ::libc::read(fd, b.as_ptr() as *mut ::libc::c_void, ::std::mem::size_of::<Arr>() as ::libc::size_t) };
-    trace!("read {} from fd {}", e, fd);
-    if e == -1 { return Err(io::Error::last_os_error()) }
-    assert!(e > 0);
+    let mut b: Arr = [0; 32];
+    loop {
+        let e = unsafe { ::libc::read(fd, b.as_mut_ptr() as *mut ::libc::c_void, ::std::mem::size_of::<Arr>() as ::libc::size_t) };
+        trace!("read {} from fd {}", e, fd);
+        if e != -1 { return Ok(e > 0) };
+        let err = io::Error::last_os_error();
+        match err.kind() {
+            io::ErrorKind::Interrupted => {},
+            io::ErrorKind::WouldBlock => return Ok(false),
+            _ => return Err(err),
+        }
+    }
+}
+
+/// How often to check whether the peer is alive, when sleeping on a Signal without an fd.
+const PEER_CHECK_INTERVAL_MS: u64 = 100;
+
+/// Reads a wakeup that we know the other side has sent (or is just about to send).
+/// If the other side dies before sending it, returns PeerDied; if it has closed and the
+/// wakeup still hasn't arrived, gives up waiting for it.
+fn consume_signal<S: Signal, F: Fn() -> bool>(s: &S, peer: &mut Peer, id: Option<Identity>, closed: F) -> io::Result<()> {
+    let interval = Duration::from_millis(PEER_CHECK_INTERVAL_MS);
+    while !try!(s.clear()) {
+        let woken = try!(peer.with_current(id, |p| {
+            if let Some(p) = p { if try!(p.is_dead()) { return Err(::ringbuf::Error::PeerDied.into()) } };
+            sleep(s, p.map(|p| p.fd()), Some(Instant::now() + interval))
+        }));
+        if !woken && closed() { break };
+    }
     Ok(())
 }
 
-impl<T, U> Sender<T, U> {
+/// Sleeps until the signal is notified (or the peer's pidfd becomes readable).
+/// Returns false on timeout.
+fn sleep<S: Signal>(s: &S, peer_fd: Option<RawFd>, deadline: Option<Instant>) -> io::Result<bool> {
+    if let Some(fd) = s.as_raw_fd() { return poll_fds(fd, peer_fd, deadline) };
+    let now = Instant::now();
+    let timeout = match deadline {
+        Some(d) if now >= d => return Ok(false),
+        Some(d) => Some(d - now),
+        None => None,
+    };
+    // There's no fd to poll together with the pidfd, so wake up now and then to check the peer.
+    let interval = Duration::from_millis(PEER_CHECK_INTERVAL_MS);
+    if peer_fd.is_some() && timeout.map(|t| t > interval).unwrap_or(true) {
+        return s.wait(Some(interval)).map(|_| true);
+    }
+    s.wait(timeout)
+}
+
+/// The timeout to give poll (or epoll_wait), or None if the deadline has passed.
+/// Rounded up, so we don't wake up just before the deadline.
+pub(crate) fn timeout_ms(deadline: Option<Instant>) -> Option<::libc::c_int> {
+    let d = match deadline { Some(d) => d, None => return Some(-1) };
+    let now = Instant::now();
+    if now >= d { return None };
+    let ms = (d - now).as_nanos().div_ceil(1_000_000);
+    Some(::std::cmp::min(ms, ::libc::c_int::MAX as u128) as ::libc::c_int)
+}
+
+/// Waits for the wait fd (or the peer's pidfd, if any) to become readable.
+/// Returns false on timeout.
+fn poll_fds(wait_fd: RawFd, peer_fd: Option<RawFd>, deadline: Option<Instant>) -> io::Result<bool> {
+    let mut fds = [::libc::pollfd { fd: wait_fd, events: ::libc::POLLIN, revents: 0 },
+                   ::libc::pollfd { fd: peer_fd.unwrap_or(-1), events: ::libc::POLLIN, revents: 0 }];
+    loop {
+        let timeout = match timeout_ms(deadline) { Some(t) => t, None => return Ok(false) };
+        let e = unsafe { ::libc::poll(fds.as_mut_ptr(), 2, timeout) };
+        if e == -1 {
+            let err = io::Error::last_os_error();
+            if err.kind() == io::ErrorKind::Interrupted { continue };
+            return Err(err);
+        }
+        if e > 0 { return Ok(true) };
+        if deadline.is_none() { continue };
+    }
+}
+
+fn unsupported() -> io::Error {
+    io::Error::new(io::ErrorKind::Unsupported, "This Signal has no fd and can't wake up tasks")
+}
+
+fn timed_out() -> io::Error { io::Error::new(io::ErrorKind::TimedOut, "Timed out waiting for the ringbuffer") }
+
+impl<T, U, S: Signal> Sender<T, U, S> {
+    fn new(inner: ::ringbuf::Sender<T, U>, signal: S, wait: S) -> Sender<T, U, S> {
+        Sender { inner: inner, signal: signal, wait: wait, peer: Default::default(), sleeping: Cell::new(false),
+            strategy: Default::default(), _fds: None }
+    }
 
     /// Returns number of items that can be written to the buffer (until it's full).
     /// f: This closure returns a tuple of (items written, please call me again).
     /// The pointer sent to the closure is an "out" parameter and contains
     /// garbage data on entering the closure. The usize parameter is the number of items that
     /// can be filled.
+    ///
+    /// Returns an error of kind BrokenPipe if the receiver has been closed, or of kind
+    /// ConnectionReset if the buffer has been reinitialized.
     pub fn send<F: FnMut(*mut T, usize) -> (usize, bool)>(&mut self, mut f: F) -> io::Result<usize> {
         let mut r = 0;
         let mut last;
-        let mut was_empty = false;
         loop {
             let mut repeat = false;
-            let (ll, wempty) = self.inner.send(|buf, s| {
+            let (ll, _) = try!(self.inner.send(|buf, s| {
                 let (rr, rep) = f(buf, s);
                 repeat = rep;
                 r += rr;
                 rr
-            });
+            }));
             last = ll;
-            was_empty |= wempty;
             if !repeat { break; }
         }
-        if r > 0 && was_empty { try!(write_fd(self.signal_fd)) };
+        if r > 0 && self.inner.wake_needed() { try!(self.signal.notify()) };
         Ok(last)
     }
 
@@ -78,98 +286,660 @@ impl<T, U> Sender<T, U> {
     /// Returns number of items that can be written to the buffer (0 means the buffer is full).
     pub fn send_foreach<F: FnMut(usize) -> T>(&mut self, count: usize, mut f: F) -> io::Result<usize> {
         let mut w = 0;
-        let (mut free_items, mut was_empty) = self.inner.send_foreach(count, |_| { w += 1; f(w - 1) });
+        let (mut free_items, _) = try!(self.inner.send_foreach(count, |_| { w += 1; f(w - 1) }));
         if free_items > 0 && w < count {
-            let (freeitems, wempty) = self.inner.send_foreach(count - w, |_| { w += 1; f(w - 1) });
-            was_empty |= wempty;
+            let (freeitems, _) = try!(self.inner.send_foreach(count - w, |_| { w += 1; f(w - 1) }));
             free_items = freeitems;
         }
 
-        if count > 0 && was_empty { try!(write_fd(self.signal_fd)) };
+        if w > 0 && self.inner.wake_needed() { try!(self.signal.notify()) };
         Ok(free_items)
     }
 
 
-    /// Returns fd to wait for, and number of items that can be written
-    /// You should only wait for this fd if the number is zero.
+    /// Returns fd to wait for (or -1 if the Signal has no fd), and number of items that can be written.
+    /// Call this right before going to sleep, it tells the receiver to wake you up.
+    /// You should only wait for this fd if the number is zero. (It is never zero if
+    /// the receiver has been closed, since send will return an error rather than block.)
     /// The Fd will not change during the lifetime of the sender.
     pub fn wait_status(&self) -> (RawFd, usize) {
-        (self.wait_fd, self.inner.write_count())
+        let mut n = self.inner.write_count();
+        if n == 0 && !self.sleeping.replace(true) { n = self.inner.prepare_sleep().unwrap_or(1) }
+        if n == 0 && self.inner.is_disconnected() { n = 1 }
+        (self.wait.as_raw_fd().unwrap_or(-1), n)
+    }
+
+    /// For async code: returns the number of items that can be written, or Pending if there
+    /// are none, in which case the task is woken up when there is room for at least one item.
+    /// Signals that can wake up tasks (such as `signal::Condvar`) do so themselves; for fd Signals,
+    /// a helper thread polls the fds (one for the whole process, started on first use).
+    /// Signals with neither (such as `signal::Futex`) return an `Unsupported` error.
+    pub fn poll_wait(&mut self, cx: &mut Context) -> Poll<io::Result<usize>> {
+        let n = match self.wait_ready() {
+            Ok(n) => n,
+            Err(e) => return Poll::Ready(Err(e)),
+        };
+        if n > 0 { return Poll::Ready(Ok(n)) };
+        if self.wait.register_waker(cx.waker()) { return Poll::Pending };
+        match self.register_fds(cx.waker()) {
+            Ok(()) => Poll::Pending,
+            Err(e) => Poll::Ready(Err(e)),
+        }
+    }
+
+    /// Has the waiter thread wake up the task, see poll_wait.
+    fn register_fds(&mut self, waker: &Waker) -> io::Result<()> {
+        let wait = try!(self.wait_fd().ok_or_else(unsupported));
+        let peer = try!(if self.peer_changed() { self.reopen_peer_fd() } else { self.peer_fd() });
+        ::waiter::register(wait, peer, waker)
+    }
+
+    /// The fd to wait for, if the Signal has one. Unlike wait_status, this has no side effects.
+    pub fn wait_fd(&self) -> Option<RawFd> { self.wait.as_raw_fd() }
+
+    /// For use with event loops: call this once after registering the wait fd, and then every
+    /// time it becomes readable. It clears the wakeup and returns the number of items that can be written;
+    /// if this is zero, go back to waiting for the next event.
+    pub fn wait_ready(&mut self) -> io::Result<usize> {
+        try!(self.wait_clear());
+        try!(self.check_peer());
+        Ok(self.wait_status().1)
     }
 
     /// Call this after woken up by the waitfd, or you'll just wake up again.
-    /// Note: Might deadlock if you call it when not woken up by the waitfd.
+    /// It is safe to call at any time; it only reads from the fd if the receiver
+    /// has sent (or is about to send) a wakeup. Returns an error of kind ConnectionAborted
+    /// if the receiver's process dies before sending it.
     pub fn wait_clear(&mut self) -> io::Result<()> {
-        flush_fd(self.wait_fd)
+        if !self.sleeping.replace(false) || self.inner.cancel_sleep() { return Ok(()) };
+        let inner = &self.inner;
+        consume_signal(&self.wait, &mut self.peer, inner.peer(), || inner.is_disconnected())
+    }
+
+    /// Like send, but if the buffer is full, waits until there is room for at least one item.
+    pub fn send_blocking<F: FnMut(*mut T, usize) -> (usize, bool)>(&mut self, f: F) -> io::Result<usize> {
+        self.send_wait(None, f)
+    }
+
+    /// Like send_blocking, but returns an error of kind TimedOut if the buffer
+    /// is still full after the timeout.
+    pub fn send_timeout<F: FnMut(*mut T, usize) -> (usize, bool)>(&mut self, timeout: Duration, f: F) -> io::Result<usize> {
+        self.send_wait(Some(Instant::now() + timeout), f)
+    }
+
+    fn send_wait<F: FnMut(*mut T, usize) -> (usize, bool)>(&mut self, deadline: Option<Instant>, mut f: F) -> io::Result<usize> {
+        loop {
+            let mut called = false;
+            let n = try!(self.send(|p, c| { called = true; f(p, c) }));
+            if called { return Ok(n) };
+            try!(self.wait_until(deadline));
+        }
+    }
+
+    /// Sets how the blocking functions (and wait) should wait.
+    pub fn set_wait_strategy(&mut self, strategy: WaitStrategy) { self.strategy = strategy }
+
+    /// Waits until there is room for at least one item (or the receiver has been closed),
+    /// according to the WaitStrategy. Returns how the wait ended, or an error of kind TimedOut.
+    pub fn wait(&mut self, timeout: Option<Duration>) -> io::Result<Wakeup> {
+        self.wait_until(timeout.map(|t| Instant::now() + t))
+    }
+
+    fn ready(&self) -> bool { self.inner.write_count() > 0 || self.inner.is_disconnected() }
+
+    fn wait_until(&mut self, deadline: Option<Instant>) -> io::Result<Wakeup> {
+        if self.ready() { return Ok(Wakeup::Immediate) };
+        if let Some(w) = spin(&self.strategy, || self.ready()) { return Ok(w) };
+        loop {
+            try!(self.check_peer());
+            // wait_status tells the receiver to wake us up, and then checks again,
+            // so we can't miss a wakeup.
+            let woken = if self.wait_status().1 == 0 {
+                let (wait, id) = (&self.wait, self.inner.peer());
+                try!(self.peer.with_current(id, |p| sleep(wait, p.map(|p| p.fd()), deadline)))
+            } else { true };
+            try!(self.wait_clear());
+            if !woken { return Err(timed_out()) };
+            if self.ready() { return Ok(Wakeup::Sleep) };
+        }
+    }
+
+    /// Closes the sender and wakes up the receiver. The receiver can still read
+    /// the remaining items, after that it will get an error of kind BrokenPipe.
+    /// This is also done automatically when the sender is dropped.
+    pub fn close(&mut self) -> io::Result<()> {
+        self.inner.close();
+        if self.inner.wake_needed() { self.signal.notify() } else { Ok(()) }
+    }
+
+    /// Returns true if the receiver has been closed (or dropped).
+    pub fn is_disconnected(&self) -> bool { self.inner.is_disconnected() }
+
+    /// Returns a pidfd for the process owning the receiver, if it's another process.
+    /// The pidfd becomes readable when that process dies; wait for it together with the
+    /// wait fd, and call `check_peer` when woken up.
+    /// The Fd stays open (and the same) until `reopen_peer_fd` is called, even if the other side
+    /// is handed off to another process (see `shm::handoff_receiver` and `peer_changed`).
+    pub fn peer_fd(&mut self) -> io::Result<Option<RawFd>> { self.peer.expose(self.inner.peer()) }
+
+    /// Returns true if the other side has been handed off to another process since `peer_fd`
+    /// was called. If so, deregister the old peer fd, and register the one from `reopen_peer_fd`
+    /// instead. (The handoff wakes us up, so checking after every wakeup is enough.)
+    pub fn peer_changed(&self) -> bool { self.peer.changed(self.inner.peer()) }
+
+    /// Closes the old pidfd, and returns one for the process now owning the receiver.
+    pub fn reopen_peer_fd(&mut self) -> io::Result<Option<RawFd>> {
+        self.peer.exposed = true;
+        self.peer.reopen(self.inner.peer())
     }
 
+    /// Returns an error of kind ConnectionAborted if the process owning the receiver
+    /// has died. This works even if the process was killed before it could close its side.
+    pub fn check_peer(&mut self) -> io::Result<()> { self.peer.check(self.inner.peer()) }
 }
 
-impl<T, U> Receiver<T, U> {
+impl<T, U, S: Signal> Drop for Sender<T, U, S> {
+    fn drop(&mut self) {
+        if let Some(fd) = self.wait.as_raw_fd() { ::waiter::deregister(fd) };
+        if self.inner.is_detached() { return };
+        if let Err(e) = self.close() { debug!("Failed to wake up receiver on close: {}", e) }
+    }
+}
+
+/// Everything another process needs to take over an endpoint, see `shm::handoff_sender`.
+pub(crate) struct Handoff<'a, U: 'a> {
+    pub mem: &'a U,
+    pub signal: Pipe,
+    pub wait: Pipe,
+    pub index: usize,
+    pub generation: usize,
+    pub sleeping: bool,
+}
+
+impl<T, U> Sender<T, U> {
+    pub(crate) fn handoff<'a>(&'a self) -> Handoff<'a, U> {
+        let (index, generation) = self.inner.position();
+        Handoff { mem: self.inner.owner(), signal: self.signal, wait: self.wait,
+            index: index, generation: generation, sleeping: self.sleeping.get() }
+    }
+
+    /// Drops the sender without closing it, as another process has taken over.
+    pub(crate) fn detach(mut self) { self.inner.detach() }
+
+    /// Records the process owning the other side, before it has attached itself.
+    pub(crate) fn set_peer(&self, id: Identity) { self.inner.set_peer(id) }
+
+    /// Records this process as the owner again, after a failed handoff.
+    pub(crate) fn reclaim(&self) -> io::Result<()> { self.inner.reclaim().map_err(Into::into) }
+
+    /// Records the process taking over, right before detaching.
+    pub(crate) fn set_owner(&self, id: Identity) { self.inner.set_owner(id) }
+}
+
+/// Takes over a sender handed off from another process.
+pub(crate) fn resume_sender<T: Send + Copy, U: Send + DerefMut<Target=[u8]>>(mem: U, index: usize, generation: usize, sleeping: bool, empty: OwnedPipe, full: OwnedPipe) -> io::Result<Sender<T, U>> {
+    let s = try!(::ringbuf::resume_sender(mem, index, generation));
+    let mut s = Sender::new(s, empty.as_pipe(), full.as_pipe());
+    s.sleeping.set(sleeping);
+    s._fds = Some(Arc::new((empty, full)));
+    // If the other side is waiting, wake it up, so that it notices the new peer (see peer_changed).
+    if s.inner.wake_needed() { try!(s.signal.notify()) };
+    Ok(s)
+}
+
+impl<T, U, S: Signal> Receiver<T, U, S> {
+    fn new(inner: ::ringbuf::Receiver<T, U>, signal: S, wait: S) -> Receiver<T, U, S> {
+        Receiver { inner: inner, signal: signal, wait: wait, peer: Default::default(), sleeping: Cell::new(false),
+            strategy: Default::default(), _fds: None }
+    }
 
     /// Returns remaining items that can be read.
-    /// The second item is true if the buffer was full but read from
-    /// (this can be used to signal remote side that more data can be written).
     /// f: This closure returns a tuple of (items written, please call me again).
+    ///
+    /// Returns an error of kind BrokenPipe if the sender has been closed and there
+    /// are no more items to read, or of kind ConnectionReset if the buffer has been reinitialized.
     pub fn recv<F: FnMut(&[T]) -> (usize, bool)>(&mut self, mut f: F) -> io::Result<usize> {
         let mut r = 0;
         let mut last;
-        let mut was_full = false;
         loop {
             let mut repeat = false;
-            let (ll, wfull) = self.inner.recv(|buf| {
+            let res = self.inner.recv(|buf| {
                 let (rr, rep) = f(buf);
                 repeat = rep;
                 r += rr;
                 rr
             });
+            // Report the disconnect on the next call, if we've already read something.
+            let (ll, _) = match res {
+                Err(_) if r > 0 => (0, false),
+                _ => try!(res),
+            };
             last = ll;
-            was_full |= wfull;
             if !repeat { break; }
         }
 
-        if r > 0 && was_full { try!(write_fd(self.signal_fd)) };
+        if r > 0 && self.inner.wake_needed() { try!(self.signal.notify()) };
         Ok(last)
     }
 
-    /// Returns fd to wait for, and number of items that can be read
-    /// You should only wait for this fd if the number is zero.
-    /// The Fd will not change during the lifetime of the sender.
+    /// Returns fd to wait for (or -1 if the Signal has no fd), and number of items that can be read.
+    /// Call this right before going to sleep, it tells the sender to wake you up.
+    /// You should only wait for this fd if the number is zero. (It is never zero if
+    /// the sender has been closed, since recv will return an error rather than block.)
+    /// The Fd will not change during the lifetime of the receiver.
     pub fn wait_status(&self) -> (RawFd, usize) {
-        (self.wait_fd, self.inner.read_count())
+        let mut n = self.inner.read_count();
+        if n == 0 && !self.sleeping.replace(true) { n = self.inner.prepare_sleep().unwrap_or(1) }
+        if n == 0 && self.inner.is_disconnected() { n = 1 }
+        (self.wait.as_raw_fd().unwrap_or(-1), n)
+    }
+
+    /// For async code: returns the number of items that can be read, or Pending if there
+    /// are none, in which case the task is woken up when there is at least one item to read.
+    /// Signals that can wake up tasks (such as `signal::Condvar`) do so themselves; for fd Signals,
+    /// a helper thread polls the fds (one for the whole process, started on first use).
+    /// Signals with neither (such as `signal::Futex`) return an `Unsupported` error.
+    pub fn poll_wait(&mut self, cx: &mut Context) -> Poll<io::Result<usize>> {
+        let n = match self.wait_ready() {
+            Ok(n) => n,
+            Err(e) => return Poll::Ready(Err(e)),
+        };
+        if n > 0 { return Poll::Ready(Ok(n)) };
+        if self.wait.register_waker(cx.waker()) { return Poll::Pending };
+        match self.register_fds(cx.waker()) {
+            Ok(()) => Poll::Pending,
+            Err(e) => Poll::Ready(Err(e)),
+        }
+    }
+
+    /// Has the waiter thread wake up the task, see poll_wait.
+    fn register_fds(&mut self, waker: &Waker) -> io::Result<()> {
+        let wait = try!(self.wait_fd().ok_or_else(unsupported));
+        let peer = try!(if self.peer_changed() { self.reopen_peer_fd() } else { self.peer_fd() });
+        ::waiter::register(wait, peer, waker)
+    }
+
+    /// The fd to wait for, if the Signal has one. Unlike wait_status, this has no side effects.
+    pub fn wait_fd(&self) -> Option<RawFd> { self.wait.as_raw_fd() }
+
+    /// For use with event loops: call this once after registering the wait fd, and then every
+    /// time it becomes readable. It clears the wakeup and returns the number of items that can be read;
+    /// if this is zero, go back to waiting for the next event.
+    pub fn wait_ready(&mut self) -> io::Result<usize> {
+        try!(self.wait_clear());
+        try!(self.check_peer());
+        Ok(self.wait_status().1)
     }
 
     /// Call this after woken up by the waitfd, or you'll just wake up again.
-    /// Note: Might deadlock if you call it when not woken up by the waitfd.
+    /// It is safe to call at any time; it only reads from the fd if the sender
+    /// has sent (or is about to send) a wakeup. Returns an error of kind ConnectionAborted
+    /// if the sender's process dies before sending it.
     pub fn wait_clear(&mut self) -> io::Result<()> {
-        flush_fd(self.wait_fd)
+        if !self.sleeping.replace(false) || self.inner.cancel_sleep() { return Ok(()) };
+        let inner = &self.inner;
+        consume_signal(&self.wait, &mut self.peer, inner.peer(), || inner.is_disconnected())
+    }
+
+    /// Like recv, but if the buffer is empty, waits until there is at least one item to read.
+    pub fn recv_blocking<F: FnMut(&[T]) -> (usize, bool)>(&mut self, f: F) -> io::Result<usize> {
+        self.recv_wait(None, f)
+    }
+
+    /// Like recv_blocking, but returns an error of kind TimedOut if the buffer
+    /// is still empty after the timeout.
+    pub fn recv_timeout<F: FnMut(&[T]) -> (usize, bool)>(&mut self, timeout: Duration, f: F) -> io::Result<usize> {
+        self.recv_wait(Some(Instant::now() + timeout), f)
+    }
+
+    fn recv_wait<F: FnMut(&[T]) -> (usize, bool)>(&mut self, deadline: Option<Instant>, mut f: F) -> io::Result<usize> {
+        loop {
+            let mut called = false;
+            let n = try!(self.recv(|d| { called = true; f(d) }));
+            if called { return Ok(n) };
+            try!(self.wait_until(deadline));
+        }
+    }
+
+    /// Sets how the blocking functions (and wait) should wait.
+    pub fn set_wait_strategy(&mut self, strategy: WaitStrategy) { self.strategy = strategy }
+
+    /// Waits until there is at least one item to read (or the sender has been closed),
+    /// according to the WaitStrategy. Returns how the wait ended, or an error of kind TimedOut.
+    pub fn wait(&mut self, timeout: Option<Duration>) -> io::Result<Wakeup> {
+        self.wait_until(timeout.map(|t| Instant::now() + t))
+    }
+
+    fn ready(&self) -> bool { self.inner.read_count() > 0 || self.inner.is_disconnected() }
+
+    fn wait_until(&mut self, deadline: Option<Instant>) -> io::Result<Wakeup> {
+        if self.ready() { return Ok(Wakeup::Immediate) };
+        if let Some(w) = spin(&self.strategy, || self.ready()) { return Ok(w) };
+        loop {
+            try!(self.check_peer());
+            // wait_status tells the sender to wake us up, and then checks again,
+            // so we can't miss a wakeup.
+            let woken = if self.wait_status().1 == 0 {
+                let (wait, id) = (&self.wait, self.inner.peer());
+                try!(self.peer.with_current(id, |p| sleep(wait, p.map(|p| p.fd()), deadline)))
+            } else { true };
+            try!(self.wait_clear());
+            if !woken { return Err(timed_out()) };
+            if self.ready() { return Ok(Wakeup::Sleep) };
+        }
+    }
+
+    /// Closes the receiver and wakes up the sender, which will get an error
+    /// of kind BrokenPipe on the next send.
+    /// This is also done automatically when the receiver is dropped.
+    pub fn close(&mut self) -> io::Result<()> {
+        self.inner.close();
+        if self.inner.wake_needed() { self.signal.notify() } else { Ok(()) }
+    }
+
+    /// Returns true if the sender has been closed (or dropped).
+    /// There might still be items left to read.
+    pub fn is_disconnected(&self) -> bool { self.inner.is_disconnected() }
+
+    /// Returns a pidfd for the process owning the sender, if it's another process.
+    /// The pidfd becomes readable when that process dies; wait for it together with the
+    /// wait fd, and call `check_peer` when woken up.
+    /// The Fd stays open (and the same) until `reopen_peer_fd` is called, even if the other side
+    /// is handed off to another process (see `shm::handoff_sender` and `peer_changed`).
+    pub fn peer_fd(&mut self) -> io::Result<Option<RawFd>> { self.peer.expose(self.inner.peer()) }
+
+    /// Returns true if the other side has been handed off to another process since `peer_fd`
+    /// was called. If so, deregister the old peer fd, and register the one from `reopen_peer_fd`
+    /// instead. (The handoff wakes us up, so checking after every wakeup is enough.)
+    pub fn peer_changed(&self) -> bool { self.peer.changed(self.inner.peer()) }
+
+    /// Closes the old pidfd, and returns one for the process now owning the sender.
+    pub fn reopen_peer_fd(&mut self) -> io::Result<Option<RawFd>> {
+        self.peer.exposed = true;
+        self.peer.reopen(self.inner.peer())
+    }
+
+    /// Returns an error of kind ConnectionAborted if the process owning the sender
+    /// has died. This works even if the process was killed before it could close its side.
+    pub fn check_peer(&mut self) -> io::Result<()> { self.peer.check(self.inner.peer()) }
+}
+
+impl<T, U, S: Signal> Drop for Receiver<T, U, S> {
+    fn drop(&mut self) {
+        if let Some(fd) = self.wait.as_raw_fd() { ::waiter::deregister(fd) };
+        if self.inner.is_detached() { return };
+        if let Err(e) = self.close() { debug!("Failed to wake up sender on close: {}", e) }
+    }
+}
+
+impl<T, U> Receiver<T, U> {
+    pub(crate) fn handoff<'a>(&'a self) -> Handoff<'a, U> {
+        let (index, generation) = self.inner.position();
+        Handoff { mem: self.inner.owner(), signal: self.signal, wait: self.wait,
+            index: index, generation: generation, sleeping: self.sleeping.get() }
     }
 
+    pub(crate) fn detach(mut self) { self.inner.detach() }
+
+    /// Records the process owning the other side, before it has attached itself.
+    pub(crate) fn set_peer(&self, id: Identity) { self.inner.set_peer(id) }
+
+    pub(crate) fn reclaim(&self) -> io::Result<()> { self.inner.reclaim().map_err(Into::into) }
+
+    /// Records the process taking over, right before detaching.
+    pub(crate) fn set_owner(&self, id: Identity) { self.inner.set_owner(id) }
 }
 
+/// Takes over a receiver handed off from another process.
+pub(crate) fn resume_receiver<T: Send + Copy, U: Send + DerefMut<Target=[u8]>>(mem: U, index: usize, generation: usize, sleeping: bool, empty: OwnedPipe, full: OwnedPipe) -> io::Result<Receiver<T, U>> {
+    let r = try!(::ringbuf::resume_receiver(mem, index, generation));
+    let mut r = Receiver::new(r, full.as_pipe(), empty.as_pipe());
+    r.sleeping.set(sleeping);
+    r._fds = Some(Arc::new((empty, full)));
+    // If the other side is waiting, wake it up, so that it notices the new peer (see peer_changed).
+    if r.inner.wake_needed() { try!(r.signal.notify()) };
+    Ok(r)
+}
+
+/// A pair of fds to signal through. Both must be non-blocking: `channel`, `channel_owned`,
+/// `attach_sender` and `attach_receiver` set O_NONBLOCK on them (which affects every fd
+/// sharing the open file), but the `_with_signal` functions leave that to the caller.
 #[derive(Debug, Copy, Clone)]
 pub struct Pipe {
     pub reader: RawFd,
     pub writer: RawFd,
 }
 
+pub(crate) fn cvt(e: ::libc::c_int) -> io::Result<::libc::c_int> {
+    if e == -1 { Err(io::Error::last_os_error()) } else { Ok(e) }
+}
+
+/// Works for eventfds (where reader and writer is the same fd), pipes and socketpairs.
+impl Signal for Pipe {
+    fn notify(&self) -> io::Result<()> { write_fd(self.writer) }
+
+    fn wait(&self, timeout: Option<Duration>) -> io::Result<bool> {
+        poll_fds(self.reader, None, timeout.map(|t| Instant::now() + t))
+    }
+
+    fn clear(&self) -> io::Result<bool> { flush_fd(self.reader) }
+
+    fn as_raw_fd(&self) -> Option<RawFd> { Some(self.reader) }
+}
+
+/// These create new fds, with O_CLOEXEC and O_NONBLOCK set.
+/// The caller is responsible for closing them (or handing them over to an OwnedPipe).
+impl Pipe {
+    /// Reader and writer is the same eventfd. This is the fastest option.
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    pub fn eventfd() -> io::Result<Pipe> {
+        let fd = try!(cvt(unsafe { ::libc::eventfd(0, ::libc::EFD_CLOEXEC | ::libc::EFD_NONBLOCK) }));
+        Ok(Pipe { reader: fd, writer: fd })
+    }
+
+    /// An anonymous pipe.
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    pub fn anonymous() -> io::Result<Pipe> {
+        let mut fds = [-1; 2];
+        try!(cvt(unsafe { ::libc::pipe2(fds.as_mut_ptr(), ::libc::O_CLOEXEC | ::libc::O_NONBLOCK) }));
+        Ok(Pipe { reader: fds[0], writer: fds[1] })
+    }
+
+    #[cfg(not(any(target_os = "linux", target_os = "android")))]
+    pub fn anonymous() -> io::Result<Pipe> {
+        let mut fds = [-1; 2];
+        try!(cvt(unsafe { ::libc::pipe(fds.as_mut_ptr()) }));
+        set_cloexec_nonblock(fds)
+    }
+
+    /// Unlike pipes and eventfds, unix sockets can be sent to another process over a unix socket.
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    pub fn socketpair() -> io::Result<Pipe> {
+        let mut fds = [-1; 2];
+        try!(cvt(unsafe { ::libc::socketpair(::libc::AF_UNIX,
+            ::libc::SOCK_STREAM | ::libc::SOCK_CLOEXEC | ::libc::SOCK_NONBLOCK, 0, fds.as_mut_ptr()) }));
+        Ok(Pipe { reader: fds[0], writer: fds[1] })
+    }
+
+    #[cfg(not(any(target_os = "linux", target_os = "android")))]
+    pub fn socketpair() -> io::Result<Pipe> {
+        let mut fds = [-1; 2];
+        try!(cvt(unsafe { ::libc::socketpair(::libc::AF_UNIX, ::libc::SOCK_STREAM, 0, fds.as_mut_ptr()) }));
+        set_cloexec_nonblock(fds)
+    }
+}
+
+/// Where the flags can't be given when creating the fds. Closes them on failure.
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+fn set_cloexec_nonblock(fds: [RawFd; 2]) -> io::Result<Pipe> {
+    for &fd in &fds {
+        let r = cvt(unsafe { ::libc::fcntl(fd, ::libc::F_SETFD, ::libc::FD_CLOEXEC) })
+            .and_then(|_| set_nonblock(fd));
+        if let Err(e) = r {
+            unsafe { ::libc::close(fds[0]); ::libc::close(fds[1]) };
+            return Err(e);
+        }
+    }
+    Ok(Pipe { reader: fds[0], writer: fds[1] })
+}
+
+fn set_nonblock(fd: RawFd) -> io::Result<()> {
+    let flags = try!(cvt(unsafe { ::libc::fcntl(fd, ::libc::F_GETFL) }));
+    if flags & ::libc::O_NONBLOCK == 0 { try!(cvt(unsafe { ::libc::fcntl(fd, ::libc::F_SETFL, flags | ::libc::O_NONBLOCK) })); }
+    Ok(())
+}
+
+/// Like Pipe, but owns its fds, which are closed when it is dropped.
+/// If reader and writer is the same fd (e g an eventfd), it is only closed once.
+#[derive(Debug)]
+pub struct OwnedPipe {
+    reader: OwnedFd,
+    writer: Option<OwnedFd>,
+}
+
+impl OwnedPipe {
+    pub fn new(reader: OwnedFd, writer: OwnedFd) -> OwnedPipe { OwnedPipe { reader: reader, writer: Some(writer) } }
+
+    /// Use the same fd for both reading and writing, e g an eventfd.
+    pub fn shared(fd: OwnedFd) -> OwnedPipe { OwnedPipe { reader: fd, writer: None } }
+
+    /// Takes ownership of the fds in the Pipe.
+    ///
+    /// # Safety
+    ///
+    /// The fds must be open, and owned by nobody else: the OwnedPipe closes them when dropped,
+    /// so nothing else may use or close them after this call.
+    pub unsafe fn from_pipe(p: Pipe) -> OwnedPipe {
+        let reader = OwnedFd::from_raw_fd(p.reader);
+        if p.reader == p.writer { OwnedPipe::shared(reader) }
+        else { OwnedPipe::new(reader, OwnedFd::from_raw_fd(p.writer)) }
+    }
+
+    pub fn as_pipe(&self) -> Pipe {
+        let r = self.reader.as_raw_fd();
+        Pipe { reader: r, writer: self.writer.as_ref().map(|w| w.as_raw_fd()).unwrap_or(r) }
+    }
+}
+
+impl Pipe {
+    /// A blocking fd would make clear() block when nothing is pending.
+    fn set_nonblock(&self) -> io::Result<()> {
+        try!(set_nonblock(self.reader));
+        if self.writer != self.reader { try!(set_nonblock(self.writer)) };
+        Ok(())
+    }
+}
+
 /// Creates a channel with fd signalling.
 /// Does not take ownership of the fds - they will not be closed
-/// when Sender and Receiver goes out of scope.
+/// when Sender and Receiver goes out of scope. The fds are made non-blocking.
 pub fn channel<T: Send + Copy, U: Send + DerefMut<Target=[u8]>>(mem: U, empty: Pipe, full: Pipe) ->
         (Sender<T, U>, Receiver<T, U>) {
-    let (s, r) = ::ringbuf::channel(mem);
-    (Sender { inner: s, signal_fd: empty.writer, wait_fd: full.reader },
-     Receiver { inner: r, signal_fd: full.writer, wait_fd: empty.reader })
+    // This only fails for fds that aren't open, which fail on first use anyway.
+    for p in &[empty, full] {
+        if let Err(e) = p.set_nonblock() { debug!("Failed to make {:?} non-blocking: {}", p, e) };
+    }
+    channel_with_signal(mem, empty, full)
+}
+
+/// Creates a channel with another type of Signal, see the `signal` module.
+/// The "empty" signal wakes up the receiver, and the "full" signal wakes up the sender.
+pub fn channel_with_signal<T: Send + Copy, U: Send + DerefMut<Target=[u8]>, S: Signal + Clone>(mem: U, empty: S, full: S) ->
+        (Sender<T, U, S>, Receiver<T, U, S>) {
+    let (s, r) = ::ringbuf::raw_channel(mem);
+    (Sender::new(s, empty.clone(), full.clone()), Receiver::new(r, full, empty))
+}
+
+/// The futexes point into the buffer, which is kept alive by the endpoint using them.
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn futexes(words: (&::std::sync::atomic::AtomicU32, &::std::sync::atomic::AtomicU32)) -> (Futex, Futex) {
+    unsafe { (Futex::new(words.0), Futex::new(words.1)) }
+}
+
+/// Creates a channel that signals through futexes in the buffer's header, instead of fds.
+/// This has lower wakeup latency. To use it between processes, put the buffer in shared memory,
+/// initialize it with ringbuf::init, and use attach_sender_futex and attach_receiver_futex instead.
+/// Use the blocking (or timeout) versions of send and recv to wait.
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub fn futex_channel<T: Send + Copy, U: Send + DerefMut<Target=[u8]>>(mem: U) -> (Sender<T, U, Futex>, Receiver<T, U, Futex>) {
+    let (s, r) = ::ringbuf::raw_channel(mem);
+    let (empty, full) = futexes(s.futexes());
+    (Sender::new(s, empty, full), Receiver::new(r, full, empty))
+}
+
+/// Like attach_sender, but signals through the futexes in the buffer's header (see futex_channel),
+/// so no fds need to be shared with the other process.
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub fn attach_sender_futex<T: Send + Copy, U: Send + DerefMut<Target=[u8]>>(mem: U) -> io::Result<Sender<T, U, Futex>> {
+    let s = try!(::ringbuf::attach_sender(mem));
+    let (empty, full) = futexes(s.futexes());
+    Ok(Sender::new(s, empty, full))
+}
+
+/// Like attach_receiver, but signals through the futexes in the buffer's header, see attach_sender_futex.
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub fn attach_receiver_futex<T: Send + Copy, U: Send + DerefMut<Target=[u8]>>(mem: U) -> io::Result<Receiver<T, U, Futex>> {
+    let r = try!(::ringbuf::attach_receiver(mem));
+    let (empty, full) = futexes(r.futexes());
+    Ok(Receiver::new(r, full, empty))
+}
+
+/// Creates a channel with fd signalling, which takes ownership of the fds.
+/// They will be closed when both the Sender and the Receiver have been dropped.
+pub fn channel_owned<T: Send + Copy, U: Send + DerefMut<Target=[u8]>>(mem: U, empty: OwnedPipe, full: OwnedPipe) ->
+        (Sender<T, U>, Receiver<T, U>) {
+    let (mut s, mut r) = channel(mem, empty.as_pipe(), full.as_pipe());
+    let fds = Arc::new((empty, full));
+    s._fds = Some(fds.clone());
+    r._fds = Some(fds);
+    (s, r)
+}
+
+/// Creates a sender for a buffer initialized with ringbuf::init, typically shared with
+/// another process which attaches the receiver. The sender takes ownership of the fds.
+pub fn attach_sender<T: Send + Copy, U: Send + DerefMut<Target=[u8]>>(mem: U, empty: OwnedPipe, full: OwnedPipe) -> io::Result<Sender<T, U>> {
+    try!(empty.as_pipe().set_nonblock());
+    try!(full.as_pipe().set_nonblock());
+    let mut s = try!(attach_sender_with_signal(mem, empty.as_pipe(), full.as_pipe()));
+    s._fds = Some(Arc::new((empty, full)));
+    Ok(s)
+}
+
+/// Like attach_sender, but with another type of Signal. Any fds are not closed by the sender.
+pub fn attach_sender_with_signal<T: Send + Copy, U: Send + DerefMut<Target=[u8]>, S: Signal>(mem: U, empty: S, full: S) -> io::Result<Sender<T, U, S>> {
+    let s = try!(::ringbuf::attach_sender(mem));
+    Ok(Sender::new(s, empty, full))
+}
+
+/// Creates a receiver for a buffer initialized with ringbuf::init. See attach_sender.
+pub fn attach_receiver<T: Send + Copy, U: Send + DerefMut<Target=[u8]>>(mem: U, empty: OwnedPipe, full: OwnedPipe) -> io::Result<Receiver<T, U>> {
+    try!(empty.as_pipe().set_nonblock());
+    try!(full.as_pipe().set_nonblock());
+    let mut r = try!(attach_receiver_with_signal(mem, empty.as_pipe(), full.as_pipe()));
+    r._fds = Some(Arc::new((empty, full)));
+    Ok(r)
+}
+
+/// Like attach_receiver, but with another type of Signal. Any fds are not closed by the receiver.
+pub fn attach_receiver_with_signal<T: Send + Copy, U: Send + DerefMut<Target=[u8]>, S: Signal>(mem: U, empty: S, full: S) -> io::Result<Receiver<T, U, S>> {
+    let r = try!(::ringbuf::attach_receiver(mem));
+    Ok(Receiver::new(r, full, empty))
+}
+
+/// Creates a channel with fd signalling, using two new eventfds (owned by the channel).
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub fn channel_new<T: Send + Copy, U: Send + DerefMut<Target=[u8]>>(mem: U) -> io::Result<(Sender<T, U>, Receiver<T, U>)> {
+    let empty = unsafe { OwnedPipe::from_pipe(try!(Pipe::eventfd())) };
+    let full = unsafe { OwnedPipe::from_pipe(try!(Pipe::eventfd())) };
+    Ok(channel_owned(mem, empty, full))
 }
 
 #[cfg(test)]
 mod tests {
     extern crate test;
-    extern crate nix;
-    use self::nix::sys::epoll::*;
     use std::os::unix::io::RawFd;
     use super::Pipe;
+    use signal::Signal;
 
     fn make_pipe() -> Pipe {
          let a = Pipe { reader: -1, writer: -1 };
@@ -180,15 +950,313 @@ mod tests {
     }
 
     fn make_epoll(fd: RawFd) -> RawFd {
-        let sleep = epoll_create().unwrap();
-        let event = EpollEvent { data: 0, events: EPOLLIN };
-        epoll_ctl(sleep, EpollOp::EpollCtlAdd, fd, &event).unwrap();
+        let sleep = unsafe { ::libc::epoll_create1(::libc::EPOLL_CLOEXEC) };
+        assert!(sleep > 2);
+        let mut event = ::libc::epoll_event { u64: 0, events: ::libc::EPOLLIN as u32 };
+        assert_eq!(0, unsafe { ::libc::epoll_ctl(sleep, ::libc::EPOLL_CTL_ADD, fd, &mut event) });
         sleep
     }
 
     fn wait_epoll(fd: RawFd) {
-        let mut events = [EpollEvent { data: 0, events: EPOLLIN }];
-        assert_eq!(1, epoll_wait(fd, &mut events, 5000).unwrap());
+        let mut events = [::libc::epoll_event { u64: 0, events: 0 }];
+        assert_eq!(1, unsafe { ::libc::epoll_wait(fd, events.as_mut_ptr(), 1, 5000) });
+    }
+
+    #[test]
+    fn disconnect_wakeup() {
+        let (pipe1, pipe2) = (make_pipe(), make_pipe());
+        let q = vec![0u8; ::ringbuf::channel_bufsize::<i32>(16)];
+        let (mut s, mut r) = super::channel::<i32, _>(q, pipe1, pipe2);
+        // The pipes were blocking, which would make clearing them block
+        for &fd in &[pipe1.reader, pipe1.writer, pipe2.reader, pipe2.writer] {
+            assert!(unsafe { ::libc::fcntl(fd, ::libc::F_GETFL) } & ::libc::O_NONBLOCK != 0);
+        }
+        let guard = ::std::thread::spawn(move || {
+            let waitfd = make_epoll(r.wait_status().0);
+            let mut sum = 0;
+            let err = loop {
+                match r.recv(|d| { for z in d { sum += *z }; (d.len(), false) }) {
+                    Err(e) => break e,
+                    Ok(0) => {
+                        if r.wait_status().1 == 0 { wait_epoll(waitfd) };
+                        r.wait_clear().unwrap();
+                    },
+                    Ok(_) => {},
+                }
+            };
+            unsafe { ::libc::close(waitfd) };
+            assert_eq!(err.kind(), ::std::io::ErrorKind::BrokenPipe);
+            sum
+        });
+        s.send_foreach(3, |i| i as i32 + 1).unwrap();
+        drop(s);
+        assert_eq!(guard.join().unwrap(), 6);
+        unsafe {
+            ::libc::close(pipe1.reader);
+            ::libc::close(pipe1.writer);
+            ::libc::close(pipe2.reader);
+            ::libc::close(pipe2.writer);
+        }
+    }
+
+    #[test]
+    fn owned_fds() {
+        use super::OwnedPipe;
+        let pipe1 = make_pipe();
+        let q = vec![0u8; ::ringbuf::channel_bufsize::<i32>(16)];
+        let (empty, full) = unsafe { (OwnedPipe::from_pipe(pipe1), OwnedPipe::from_pipe(Pipe::eventfd().unwrap())) };
+        let (s, r) = super::channel_owned::<i32, _>(q, empty, full);
+        assert_eq!(r.wait_status().1, 0);
+
+        // Keep a non-blocking copy of the reader, to find out when the writer is closed.
+        let dup = unsafe { ::libc::dup(pipe1.reader) };
+        assert_eq!(0, unsafe { ::libc::fcntl(dup, ::libc::F_SETFL, ::libc::O_NONBLOCK) });
+        let mut b = [0u8; 64];
+        let mut read_dup = || unsafe { ::libc::read(dup, b.as_mut_ptr() as *mut _, 64) };
+        drop(s);
+        assert_eq!(read_dup(), 8);
+        assert_eq!(read_dup(), -1);
+        drop(r);
+        assert_eq!(read_dup(), 0);
+        unsafe { ::libc::close(dup) };
+    }
+
+    #[test]
+    fn channel_new() {
+        let q = vec![0u8; ::ringbuf::channel_bufsize::<i32>(16)];
+        let (mut s, mut r) = super::channel_new::<i32, _>(q).unwrap();
+        assert!(s.wait_status().0 != r.wait_status().0);
+        assert_eq!(r.wait_status().1, 0);
+        s.send_foreach(2, |i| i as i32).unwrap();
+        wait_epoll(make_epoll(r.wait_status().0));
+        r.wait_clear().unwrap();
+        r.recv(|d| { assert_eq!(d, &[0, 1]); (2, false) }).unwrap();
+
+        for p in &[Pipe::anonymous().unwrap(), Pipe::socketpair().unwrap()] {
+            let mut b = [0u8; 8];
+            assert_eq!(8, unsafe { ::libc::write(p.writer, b.as_ptr() as *const _, 8) });
+            assert_eq!(8, unsafe { ::libc::read(p.reader, b.as_mut_ptr() as *mut _, 8) });
+            assert_eq!(-1, unsafe { ::libc::read(p.reader, b.as_mut_ptr() as *mut _, 8) });
+            unsafe { ::libc::close(p.reader); ::libc::close(p.writer) };
+        }
+    }
+
+    fn blocking_roundtrip<S>(mut s: super::Sender<u32, Vec<u8>, S>, mut r: super::Receiver<u32, Vec<u8>, S>)
+    where S: Signal + Send + 'static {
+        use std::time::Duration;
+        let e = r.recv_timeout(Duration::from_millis(10), |_| panic!()).unwrap_err();
+        assert_eq!(e.kind(), ::std::io::ErrorKind::TimedOut);
+
+        let guard = ::std::thread::spawn(move || {
+            let mut sum = 0;
+            loop {
+                match r.recv_blocking(|d| { sum += d[0]; (1, false) }) {
+                    Ok(_) => {},
+                    Err(e) => { assert_eq!(e.kind(), ::std::io::ErrorKind::BrokenPipe); return sum },
+                }
+            }
+        });
+        for i in 0..1000 {
+            s.send_blocking(|p, _| { unsafe { *p = i }; (1, false) }).unwrap();
+        }
+        drop(s);
+        assert_eq!(guard.join().unwrap(), 999 * 1000 / 2);
+    }
+
+    #[test]
+    fn blocking() {
+        let q = vec![0u8; ::ringbuf::channel_bufsize::<u32>(4)];
+        let (s, r) = super::channel_new::<u32, _>(q).unwrap();
+        blocking_roundtrip(s, r);
+    }
+
+    #[test]
+    fn futex_channel() {
+        let q = vec![0u8; ::ringbuf::channel_bufsize::<u32>(4)];
+        let (s, r) = super::futex_channel(q);
+        blocking_roundtrip(s, r);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn futex_processes() {
+        use std::io::Read;
+        use std::os::unix::io::AsRawFd;
+        let (child, socket) = ::child::spawn("fdbuf::tests::futex_processes_child");
+        let mut m = ::mmap::Mmap::sealed_memfd("test", ::channel_bufsize::<u32>(4)).unwrap();
+        ::ringbuf::init::<u32>(&mut m);
+        ::shm::send_fds(&socket, &[0], &[m.fd().unwrap().as_raw_fd()]).unwrap();
+        let mut s = super::attach_sender_futex::<u32, _>(m).unwrap();
+        // Small buffer, so both sides have to wait for each other.
+        for i in 1..101 { s.send_blocking(|p, _| { unsafe { *p = i }; (1, false) }).unwrap(); }
+        drop(s);
+        let mut sum = [0u8; 4];
+        (&socket).read_exact(&mut sum).unwrap();
+        assert_eq!(u32::from_ne_bytes(sum), 5050);
+        ::child::wait(child);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    #[ignore]
+    fn futex_processes_child() {
+        use std::io::Write;
+        let socket = match ::child::socket() { Some(s) => s, None => return };
+        let mut fds = ::shm::recv_fds(&socket, &mut [0], 1).unwrap();
+        let m = ::mmap::Mmap::from_sealed_fd(fds.pop().unwrap()).unwrap();
+        let mut r = super::attach_receiver_futex::<u32, _>(m).unwrap();
+        let mut sum = 0u32;
+        loop {
+            match r.recv_blocking(|d| { sum += d.iter().sum::<u32>(); (d.len(), false) }) {
+                Ok(_) => {},
+                Err(e) => { assert_eq!(e.kind(), ::std::io::ErrorKind::BrokenPipe); break },
+            }
+        }
+        (&socket).write_all(&sum.to_ne_bytes()).unwrap();
+    }
+
+    #[test]
+    fn wait_strategy() {
+        use std::time::Duration;
+        use super::{WaitStrategy, Wakeup};
+        let q = vec![0u8; ::ringbuf::channel_bufsize::<u32>(4)];
+        let (mut s, mut r) = super::channel_new::<u32, _>(q).unwrap();
+        assert_eq!(s.wait(None).unwrap(), Wakeup::Immediate);
+        r.set_wait_strategy(WaitStrategy { spin: 10, spin_time: None, yields: 10 });
+        let e = r.wait(Some(Duration::from_millis(10))).unwrap_err();
+        assert_eq!(e.kind(), ::std::io::ErrorKind::TimedOut);
+
+        let strategies = [(WaitStrategy { spin: !0, spin_time: Some(Duration::from_secs(5)), yields: 0 }, Wakeup::Spin),
+                          (WaitStrategy { spin: 0, spin_time: None, yields: !0 }, Wakeup::Yield),
+                          (WaitStrategy::default(), Wakeup::Sleep)];
+        for &(strategy, wakeup) in &strategies {
+            r.set_wait_strategy(strategy);
+            let guard = ::std::thread::spawn(move || {
+                ::std::thread::sleep(Duration::from_millis(20));
+                s.send_foreach(1, |_| 5).unwrap();
+                s
+            });
+            assert_eq!(r.wait(None).unwrap(), wakeup);
+            r.recv(|d| { assert_eq!(d, &[5]); (1, false) }).unwrap();
+            s = guard.join().unwrap();
+        }
+    }
+
+    #[test]
+    fn other_signals() {
+        use signal::{Condvar, Futex};
+        use std::sync::atomic::AtomicU32;
+        let q = vec![0u8; ::ringbuf::channel_bufsize::<u32>(4)];
+        let (s, r) = super::channel_with_signal(q, Condvar::new(), Condvar::new());
+        assert_eq!(s.wait_status().0, -1);
+        blocking_roundtrip(s, r);
+
+        let words = [AtomicU32::new(0), AtomicU32::new(0)];
+        let q = vec![0u8; ::ringbuf::channel_bufsize::<u32>(4)];
+        let (s, r) = unsafe { super::channel_with_signal(q, Futex::new(&words[0]), Futex::new(&words[1])) };
+        blocking_roundtrip(s, r);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn peer_changed() {
+        let q = vec![0u8; ::channel_bufsize::<u32>(4)];
+        let (mut s, _r) = super::channel_new::<u32, _>(q).unwrap();
+        assert_eq!(s.peer_fd().unwrap(), None);
+        assert!(!s.peer_changed());
+
+        // As if the receiver had been handed off to another process
+        let mut child = ::std::process::Command::new("sleep").arg("10").spawn().unwrap();
+        s.set_peer(::peer::Identity::of(child.id() as usize));
+        assert!(s.peer_changed());
+        // The old fd is kept until reopened, but the new peer is checked.
+        assert_eq!(s.peer_fd().unwrap(), None);
+        s.check_peer().unwrap();
+        let fd = s.reopen_peer_fd().unwrap().unwrap();
+        assert!(!s.peer_changed());
+        assert_eq!(s.peer_fd().unwrap(), Some(fd));
+
+        child.kill().unwrap();
+        child.wait().unwrap();
+        assert_eq!(s.check_peer().unwrap_err().kind(), ::std::io::ErrorKind::ConnectionAborted);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn peer_died() {
+        use std::io::{ErrorKind, Read};
+        use std::time::Duration;
+        let (child, socket) = ::child::spawn("fdbuf::tests::peer_died_child");
+        let mut s = ::shm::Builder::<u32>::new(4).send_receiver(&socket).unwrap();
+        // The child dies without closing anything, while we're blocked on both sides.
+        let socket2 = socket.try_clone().unwrap();
+        let t = ::std::thread::spawn(move || {
+            let mut r = ::shm::Builder::<u32>::new(4).send_sender(&socket2).unwrap();
+            // Wait until the child has attached both endpoints.
+            (&socket2).read_exact(&mut [0u8]).unwrap();
+            r.recv_timeout(Duration::from_secs(5), |_| panic!()).unwrap_err()
+        });
+        s.send_foreach(4, |i| i as u32).unwrap();
+        while s.reopen_peer_fd().unwrap().is_none() { ::std::thread::sleep(Duration::from_millis(1)) };
+        let e = s.send_timeout(Duration::from_secs(5), |_, _| panic!()).unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::ConnectionAborted);
+        assert_eq!(t.join().unwrap().kind(), ErrorKind::ConnectionAborted);
+        assert_eq!(s.wait_ready().unwrap_err().kind(), ErrorKind::ConnectionAborted);
+        ::child::wait(child);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    #[ignore]
+    fn peer_died_child() {
+        use std::io::Write;
+        let socket = match ::child::socket() { Some(s) => s, None => return };
+        let _r = ::shm::connect_receiver::<u32>(&socket).unwrap();
+        let _s = ::shm::connect_sender::<u32>(&socket).unwrap();
+        (&socket).write_all(&[1]).unwrap();
+        ::std::thread::sleep(::std::time::Duration::from_millis(100));
+        unsafe { ::libc::_exit(0) };
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn wakeup_lost() {
+        use std::io::{ErrorKind, Read, Write};
+        let (child, socket) = ::child::spawn("fdbuf::tests::wakeup_lost_child");
+        let mut s = ::shm::Builder::<u32>::new(4).send_receiver(&socket).unwrap();
+        (&socket).read_exact(&mut [0u8]).unwrap();
+        s.send_foreach(4, |i| i as u32).unwrap();
+        assert_eq!(s.wait_status().1, 0);
+        // The child sees that we're sleeping, and dies before waking us up.
+        (&socket).write_all(&[1]).unwrap();
+        (&socket).read_exact(&mut [0u8]).unwrap();
+        assert_eq!(s.wait_clear().unwrap_err().kind(), ErrorKind::ConnectionAborted);
+        ::child::wait(child);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    #[ignore]
+    fn wakeup_lost_child() {
+        use std::io::{Read, Write};
+        let socket = match ::child::socket() { Some(s) => s, None => return };
+        let r = ::shm::connect_receiver::<u32>(&socket).unwrap();
+        (&socket).write_all(&[1]).unwrap();
+        (&socket).read_exact(&mut [0u8]).unwrap();
+        assert!(r.inner.wake_needed());
+        (&socket).write_all(&[1]).unwrap();
+        unsafe { ::libc::_exit(0) };
+    }
+
+    #[test]
+    fn nonblocking_signal() {
+        let p = Pipe::anonymous().unwrap();
+        // Nothing to clear
+        assert_eq!(super::flush_fd(p.reader).unwrap(), false);
+        // Fill the pipe up, further writes should still succeed
+        for _ in 0..20000 { super::write_fd(p.writer).unwrap() };
+        assert_eq!(super::flush_fd(p.reader).unwrap(), true);
+        unsafe { ::libc::close(p.reader); ::libc::close(p.writer) };
     }
 
     #[bench]
@@ -205,14 +1273,11 @@ mod tests {
 
     #[bench]
     fn eventfd_send400_recv300_bufsize1024_u32(b: &mut test::Bencher) {
-        use self::nix::sys::eventfd::*;
-        let (efd1, efd2) = (eventfd(0, EFD_CLOEXEC).unwrap(), eventfd(0, EFD_CLOEXEC).unwrap());
-        let pipe1 = Pipe { reader: efd1, writer: efd1 };
-        let pipe2 = Pipe { reader: efd2, writer: efd2 };
+        let (pipe1, pipe2) = (Pipe::eventfd().unwrap(), Pipe::eventfd().unwrap());
         run400_300_1024_bench(b, pipe1, pipe2);
         unsafe {
-            ::libc::close(efd1);
-            ::libc::close(efd2);
+            ::libc::close(pipe1.reader);
+            ::libc::close(pipe2.reader);
         }
     }
 
@@ -233,7 +1298,7 @@ mod tests {
                 if quit { break; }
                 if can_recv == 0 {
                     debug!("Recv wait");
-                    wait_epoll(waitfd);
+                    if r.wait_status().1 == 0 { wait_epoll(waitfd) };
                     r.wait_clear().unwrap();
                 };
             };
@@ -248,7 +1313,7 @@ mod tests {
             let can_send = s.send_foreach(400, |_| { c += 1; total1 += c as u64; c }).unwrap();
             if can_send == 0 {
                  debug!("Send wait");
-                 wait_epoll(waitfd);
+                 if s.wait_status().1 == 0 { wait_epoll(waitfd) };
                  s.wait_clear().unwrap();
             };
         });
@@ -0,0 +1,187 @@
+//! futures Stream and Sink for ringbuf and fdbuf endpoints, one item at a time.
+//!
+//! These work with any executor, as the endpoints wake up the task themselves: the in-process
+//! `ringbuf` endpoints through `poll_read_count`/`poll_write_count`, and fdbuf endpoints
+//! through `poll_wait`. For fd Signals, that means a helper thread polling the fds; with tokio,
+//! tokiobuf avoids it by using tokio's reactor instead.
+
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use futures_core::Stream;
+use futures_sink::Sink;
+use fdbuf::{Sender, Receiver};
+use signal::Signal;
+use ringbuf;
+
+impl<T: Copy, U, S: Signal + Unpin> Stream for Receiver<T, U, S> {
+    type Item = io::Result<T>;
+
+    /// Ends the stream when the sender has been closed and all items have been read.
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<io::Result<T>>> {
+        let r = self.get_mut();
+        loop {
+            match r.poll_wait(cx) {
+                Poll::Pending => return Poll::Pending,
+                Poll::Ready(Err(e)) => return Poll::Ready(Some(Err(e))),
+                Poll::Ready(Ok(_)) => {},
+            }
+            let mut item = None;
+            match r.recv(|d| { item = d.first().cloned(); (d.len().min(1), false) }) {
+                Err(ref e) if e.kind() == io::ErrorKind::BrokenPipe => return Poll::Ready(None),
+                Err(e) => return Poll::Ready(Some(Err(e))),
+                Ok(_) => if let Some(i) = item { return Poll::Ready(Some(Ok(i))) },
+            }
+        }
+    }
+}
+
+impl<T, U, S: Signal + Unpin> Sink<T> for Sender<T, U, S> {
+    type Error = io::Error;
+
+    fn poll_ready(self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+        self.get_mut().poll_wait(cx).map(|r| r.map(|_| ()))
+    }
+
+    fn start_send(self: Pin<&mut Self>, item: T) -> io::Result<()> {
+        let mut item = Some(item);
+        try!(self.get_mut().send(|p, _| {
+            unsafe { ::std::ptr::write(p, item.take().unwrap()) };
+            (1, false)
+        }));
+        // send does not call us if the buffer is full
+        match item {
+            None => Ok(()),
+            Some(_) => Err(io::Error::new(io::ErrorKind::WouldBlock, "start_send called without poll_ready")),
+        }
+    }
+
+    /// Items are visible to the receiver as soon as they are sent, so there is nothing to flush.
+    fn poll_flush(self: Pin<&mut Self>, _: &mut Context) -> Poll<io::Result<()>> { Poll::Ready(Ok(())) }
+
+    fn poll_close(self: Pin<&mut Self>, _: &mut Context) -> Poll<io::Result<()>> {
+        Poll::Ready(self.get_mut().close())
+    }
+}
+
+impl<T: Copy, U> Stream for ringbuf::Receiver<T, U> {
+    type Item = Result<T, ringbuf::Error>;
+
+    /// Ends the stream when the sender has been closed and all items have been read.
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Result<T, ringbuf::Error>>> {
+        let r = self.get_mut();
+        loop {
+            match r.poll_read_count(cx) {
+                Poll::Pending => return Poll::Pending,
+                Poll::Ready(Err(ringbuf::Error::Disconnected)) => return Poll::Ready(None),
+                Poll::Ready(Err(e)) => return Poll::Ready(Some(Err(e))),
+                Poll::Ready(Ok(_)) => {},
+            }
+            let mut item = None;
+            match r.recv(|d| { item = d.first().cloned(); d.len().min(1) }) {
+                Err(ringbuf::Error::Disconnected) => return Poll::Ready(None),
+                Err(e) => return Poll::Ready(Some(Err(e))),
+                Ok(_) => if let Some(i) = item { return Poll::Ready(Some(Ok(i))) },
+            }
+        }
+    }
+}
+
+impl<T, U> Sink<T> for ringbuf::Sender<T, U> {
+    type Error = ringbuf::Error;
+
+    fn poll_ready(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<(), ringbuf::Error>> {
+        self.get_mut().poll_write_count(cx).map(|r| r.map(|_| ()))
+    }
+
+    fn start_send(self: Pin<&mut Self>, item: T) -> Result<(), ringbuf::Error> {
+        let mut item = Some(item);
+        try!(self.get_mut().send(|p, _| {
+            unsafe { ::std::ptr::write(p, item.take().unwrap()) };
+            1
+        }));
+        // send does not call us if the buffer is full
+        match item {
+            None => Ok(()),
+            Some(_) => Err(ringbuf::Error::Full),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _: &mut Context) -> Poll<Result<(), ringbuf::Error>> { Poll::Ready(Ok(())) }
+
+    fn poll_close(self: Pin<&mut Self>, _: &mut Context) -> Poll<Result<(), ringbuf::Error>> {
+        self.get_mut().close();
+        Poll::Ready(Ok(()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::{stream, SinkExt, StreamExt};
+    use futures::executor::block_on;
+    use signal::Condvar;
+
+    #[test]
+    fn condvar_stream_sink() {
+        let q = vec![0u8; ::channel_bufsize::<u32>(3)];
+        let (mut s, r) = ::fdbuf::channel_with_signal::<u32, _, _>(q, Condvar::new(), Condvar::new());
+        let sender = ::std::thread::spawn(move || {
+            block_on(s.send_all(&mut stream::iter((0..20).map(Ok)))).unwrap();
+        });
+        let v: Vec<u32> = block_on(r.map(|i| i.unwrap()).collect());
+        assert_eq!(v, (0..20).collect::<Vec<_>>());
+        sender.join().unwrap();
+    }
+
+    #[test]
+    fn ringbuf_stream_sink() {
+        let q = vec![0u8; ::channel_bufsize::<u16>(3)];
+        let (mut s, r) = ::ringbuf::channel::<u16, _>(q);
+        let sender = ::std::thread::spawn(move || {
+            block_on(s.send_all(&mut stream::iter((0..20).map(Ok)))).unwrap();
+        });
+        let v: Vec<u16> = block_on(r.map(|i| i.unwrap()).collect());
+        assert_eq!(v, (0..20).collect::<Vec<_>>());
+        sender.join().unwrap();
+    }
+
+    #[test]
+    fn ringbuf_sink_full() {
+        use futures_sink::Sink;
+        use std::pin::Pin;
+        let q = vec![0u8; ::channel_bufsize::<u16>(1)];
+        let (mut s, _r) = ::ringbuf::channel::<u16, _>(q);
+        Pin::new(&mut s).start_send(1).unwrap();
+        assert_eq!(Pin::new(&mut s).start_send(2), Err(::ringbuf::Error::Full));
+    }
+
+    #[test]
+    fn eventfd_stream_sink() {
+        let q = vec![0u8; ::channel_bufsize::<u16>(3)];
+        let (mut s, r) = ::fdbuf::channel_new::<u16, _>(q).unwrap();
+        let sender = ::std::thread::spawn(move || {
+            block_on(s.send_all(&mut stream::iter((0..20).map(Ok)))).unwrap();
+        });
+        let v: Vec<u16> = block_on(r.map(|i| i.unwrap()).collect());
+        assert_eq!(v, (0..20).collect::<Vec<_>>());
+        sender.join().unwrap();
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn eventfd_stream_peer_died() {
+        let q = vec![0u8; ::channel_bufsize::<u16>(3)];
+        let (_s, mut r) = ::fdbuf::channel_new::<u16, _>(q).unwrap();
+        // As if the sender was in another process, which then dies.
+        let mut child = ::std::process::Command::new("sleep").arg("10").spawn().unwrap();
+        r.set_peer(::peer::Identity::of(child.id() as usize));
+        let killer = ::std::thread::spawn(move || {
+            ::std::thread::sleep(::std::time::Duration::from_millis(20));
+            child.kill().unwrap();
+            child.wait().unwrap();
+        });
+        let e = block_on(r.next()).unwrap().unwrap_err();
+        assert_eq!(e.kind(), ::std::io::ErrorKind::ConnectionAborted);
+        killer.join().unwrap();
+    }
+}
@@ -5,9 +5,56 @@ extern crate libc;
 #[macro_use]
 extern crate log;
 
+#[cfg(feature = "mio")]
+extern crate mio;
+
+#[cfg(feature = "tokio")]
+extern crate tokio;
+
+#[cfg(feature = "futures")]
+extern crate futures_core;
+
+#[cfg(feature = "futures")]
+extern crate futures_sink;
+
+#[cfg(all(test, feature = "futures"))]
+extern crate futures;
+
 pub mod ringbuf;
 
 pub mod fdbuf;
 
+pub mod signal;
+
+pub mod mmap;
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub mod shm;
+
+#[cfg(not(target_os = "android"))]
+pub mod sysv;
+
+#[cfg(feature = "tokio")]
+pub mod tokiobuf;
+
+mod peer;
+
+mod waiter;
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+mod poller;
+
+#[cfg(target_os = "linux")]
+mod server;
+
+#[cfg(feature = "mio")]
+mod mio_source;
+
+#[cfg(feature = "futures")]
+mod futures_stream;
+
+#[cfg(all(test, target_os = "linux"))]
+mod child;
+
 /// Use this utility function to figure out how big u8 buffer you need to allocate for a ringbuf or fdbuf.
 pub fn channel_bufsize<T>(capacity: usize) -> usize { ringbuf::channel_bufsize::<T>(capacity) }
@@ -0,0 +1,124 @@
+//! Lets fdbuf endpoints be registered with a mio `Poll`.
+//!
+//! The wait fd is registered (and the peer's pidfd, if the peer is another process), always with
+//! readable interest, regardless of whether the endpoint is a sender or a receiver.
+//! mio is edge triggered, so call `wait_ready` after registering and after every event,
+//! and keep sending/receiving until it returns zero.
+//! If `peer_changed` returns true after an event, the other side has been handed off to another
+//! process; call `reregister`, which moves the registration over to the new process's pidfd.
+
+use std::io;
+use std::os::unix::io::RawFd;
+use mio::{Interest, Registry, Token};
+use mio::event::Source;
+use mio::unix::SourceFd;
+use fdbuf::{Sender, Receiver};
+use signal::Signal;
+
+fn register(registry: &Registry, token: Token, wait: Option<RawFd>, peer: Option<RawFd>) -> io::Result<()> {
+    let wait = try!(wait.ok_or(io::Error::new(io::ErrorKind::InvalidInput, "The Signal has no fd to register")));
+    try!(registry.register(&mut SourceFd(&wait), token, Interest::READABLE));
+    if let Some(p) = peer { try!(registry.register(&mut SourceFd(&p), token, Interest::READABLE)) };
+    Ok(())
+}
+
+fn reregister(registry: &Registry, token: Token, wait: Option<RawFd>, peer: Option<RawFd>) -> io::Result<()> {
+    let wait = try!(wait.ok_or(io::Error::new(io::ErrorKind::InvalidInput, "The Signal has no fd to register")));
+    try!(registry.reregister(&mut SourceFd(&wait), token, Interest::READABLE));
+    if let Some(p) = peer { try!(registry.reregister(&mut SourceFd(&p), token, Interest::READABLE)) };
+    Ok(())
+}
+
+/// After a handoff of the other side: swaps the old peer's pidfd for the new one's.
+fn replace_peer(registry: &Registry, token: Token, old: Option<RawFd>, new: Option<RawFd>) -> io::Result<()> {
+    if let Some(p) = old { try!(registry.deregister(&mut SourceFd(&p))) };
+    if let Some(p) = new { try!(registry.register(&mut SourceFd(&p), token, Interest::READABLE)) };
+    Ok(())
+}
+
+fn deregister(registry: &Registry, wait: Option<RawFd>, peer: Option<RawFd>) -> io::Result<()> {
+    if let Some(p) = peer { try!(registry.deregister(&mut SourceFd(&p))) };
+    match wait {
+        Some(w) => registry.deregister(&mut SourceFd(&w)),
+        None => Ok(()),
+    }
+}
+
+impl<T, U, S: Signal> Source for Sender<T, U, S> {
+    fn register(&mut self, registry: &Registry, token: Token, _: Interest) -> io::Result<()> {
+        let peer = try!(self.peer_fd());
+        register(registry, token, self.wait_fd(), peer)
+    }
+
+    fn reregister(&mut self, registry: &Registry, token: Token, _: Interest) -> io::Result<()> {
+        let peer = try!(self.peer_fd());
+        if self.peer_changed() {
+            try!(reregister(registry, token, self.wait_fd(), None));
+            let new = try!(self.reopen_peer_fd());
+            return replace_peer(registry, token, peer, new);
+        }
+        reregister(registry, token, self.wait_fd(), peer)
+    }
+
+    fn deregister(&mut self, registry: &Registry) -> io::Result<()> {
+        // If the peer's pidfd could not be opened, it was never registered either.
+        let peer = self.peer_fd().unwrap_or(None);
+        deregister(registry, self.wait_fd(), peer)
+    }
+}
+
+impl<T, U, S: Signal> Source for Receiver<T, U, S> {
+    fn register(&mut self, registry: &Registry, token: Token, _: Interest) -> io::Result<()> {
+        let peer = try!(self.peer_fd());
+        register(registry, token, self.wait_fd(), peer)
+    }
+
+    fn reregister(&mut self, registry: &Registry, token: Token, _: Interest) -> io::Result<()> {
+        let peer = try!(self.peer_fd());
+        if self.peer_changed() {
+            try!(reregister(registry, token, self.wait_fd(), None));
+            let new = try!(self.reopen_peer_fd());
+            return replace_peer(registry, token, peer, new);
+        }
+        reregister(registry, token, self.wait_fd(), peer)
+    }
+
+    fn deregister(&mut self, registry: &Registry) -> io::Result<()> {
+        let peer = self.peer_fd().unwrap_or(None);
+        deregister(registry, self.wait_fd(), peer)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use mio::{Events, Interest, Poll, Token};
+    use std::time::Duration;
+
+    #[test]
+    fn mio_poll() {
+        let q = vec![0u8; ::channel_bufsize::<u32>(4)];
+        let (mut s, mut r) = ::fdbuf::channel_new::<u32, _>(q).unwrap();
+        let mut poll = Poll::new().unwrap();
+        let mut events = Events::with_capacity(4);
+        poll.registry().register(&mut r, Token(3), Interest::READABLE).unwrap();
+        assert_eq!(r.wait_ready().unwrap(), 0);
+
+        s.send_foreach(2, |i| i as u32 + 7).unwrap();
+        poll.poll(&mut events, Some(Duration::from_secs(5))).unwrap();
+        assert_eq!(events.iter().map(|e| e.token()).collect::<Vec<_>>(), vec![Token(3)]);
+        assert_eq!(r.wait_ready().unwrap(), 2);
+        r.recv(|d| { assert_eq!(d, &[7, 8]); (2, false) }).unwrap();
+        assert_eq!(r.wait_ready().unwrap(), 0);
+
+        // Nothing sent, so no event
+        poll.poll(&mut events, Some(Duration::from_millis(10))).unwrap();
+        assert!(events.is_empty());
+
+        drop(s);
+        poll.poll(&mut events, Some(Duration::from_secs(5))).unwrap();
+        assert_eq!(events.iter().count(), 1);
+        assert_eq!(r.wait_ready().unwrap(), 1);
+        assert_eq!(r.recv(|_| (0, false)).unwrap_err().kind(), ::std::io::ErrorKind::BrokenPipe);
+        poll.registry().deregister(&mut r).unwrap();
+    }
+}
@@ -0,0 +1,305 @@
+//! Owns a shared memory mapping, to be used as the buffer of a ringbuf or fdbuf.
+//!
+//! Also has `Locked`, which keeps any buffer in RAM, for real-time use.
+
+use std::io;
+use std::ops::{Deref, DerefMut};
+use std::os::unix::io::{OwnedFd, AsRawFd, FromRawFd};
+use std::ffi::CString;
+use fdbuf::cvt;
+
+/// A MAP_SHARED memory mapping, unmapped on drop. It keeps the file it maps (if any) open,
+/// so it can be sent to other processes.
+pub struct Mmap {
+    ptr: *mut u8,
+    len: usize,
+    fd: Option<OwnedFd>,
+    huge: bool,
+}
+
+unsafe impl Send for Mmap {}
+
+impl Mmap {
+    /// Maps len bytes of the file, readable and writable.
+    pub fn from_fd(fd: OwnedFd, len: usize) -> io::Result<Mmap> {
+        let p = unsafe { ::libc::mmap(::std::ptr::null_mut(), len, ::libc::PROT_READ | ::libc::PROT_WRITE,
+            ::libc::MAP_SHARED, fd.as_raw_fd(), 0) };
+        if p == ::libc::MAP_FAILED { return Err(io::Error::last_os_error()) };
+        Ok(Mmap { ptr: p as *mut u8, len: len, fd: Some(fd), huge: false })
+    }
+
+    /// Maps the whole file.
+    pub fn from_fd_all(fd: OwnedFd) -> io::Result<Mmap> {
+        let len = try!(file_size(&fd));
+        Mmap::from_fd(fd, len)
+    }
+
+    /// Maps len bytes of anonymous memory. There is no file to send to other processes,
+    /// but the mapping stays shared with child processes after fork.
+    pub fn anonymous(len: usize) -> io::Result<Mmap> {
+        Mmap::map_anonymous(len, 0)
+    }
+
+    fn map_anonymous(len: usize, flags: ::libc::c_int) -> io::Result<Mmap> {
+        let p = unsafe { ::libc::mmap(::std::ptr::null_mut(), len, ::libc::PROT_READ | ::libc::PROT_WRITE,
+            ::libc::MAP_SHARED | ::libc::MAP_ANONYMOUS | flags, -1, 0) };
+        if p == ::libc::MAP_FAILED { return Err(io::Error::last_os_error()) };
+        Ok(Mmap { ptr: p as *mut u8, len: len, fd: None, huge: false })
+    }
+
+    /// Like anonymous, but backed by huge pages if possible, which means less TLB pressure for
+    /// large buffers. len is rounded up to a multiple of the huge page size (a ringbuf will use
+    /// the extra space for more items). If no huge pages are available, falls back to
+    /// normal pages (and len is not rounded); see is_huge.
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    pub fn huge_anonymous(len: usize) -> io::Result<Mmap> {
+        if let Some(hlen) = round_to_huge_page(len) {
+            match Mmap::map_anonymous(hlen, ::libc::MAP_HUGETLB) {
+                Ok(mut m) => { m.huge = true; return Ok(m) },
+                Err(e) => debug!("Huge pages not available, using normal pages: {}", e),
+            }
+        }
+        Mmap::anonymous(len)
+    }
+
+    /// Creates a new memfd of len bytes, and maps it. The name is only for debugging.
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    pub fn memfd(name: &str, len: usize) -> io::Result<Mmap> {
+        Mmap::new_memfd(name, len, 0)
+    }
+
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    fn new_memfd(name: &str, len: usize, flags: ::libc::c_uint) -> io::Result<Mmap> {
+        let fd = try!(memfd_create(name, ::libc::MFD_CLOEXEC | flags));
+        try!(cvt(unsafe { ::libc::ftruncate(fd.as_raw_fd(), len as ::libc::off_t) }));
+        if flags & ::libc::MFD_ALLOW_SEALING != 0 {
+            try!(cvt(unsafe { ::libc::fcntl(fd.as_raw_fd(), ::libc::F_ADD_SEALS, SEALS) }));
+        }
+        Mmap::from_fd(fd, len)
+    }
+
+    /// Like new_memfd, but with huge pages (falling back to normal pages), see huge_anonymous.
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    fn new_huge_memfd(name: &str, len: usize, flags: ::libc::c_uint) -> io::Result<Mmap> {
+        if let Some(hlen) = round_to_huge_page(len) {
+            // With no free huge pages, it's usually mmap that fails.
+            match Mmap::new_memfd(name, hlen, flags | ::libc::MFD_HUGETLB) {
+                Ok(mut m) => { m.huge = true; return Ok(m) },
+                Err(e) => debug!("Huge pages not available, using normal pages: {}", e),
+            }
+        }
+        Mmap::new_memfd(name, len, flags)
+    }
+
+    /// Like memfd, but backed by huge pages if possible, see huge_anonymous.
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    pub fn huge_memfd(name: &str, len: usize) -> io::Result<Mmap> {
+        Mmap::new_huge_memfd(name, len, 0)
+    }
+
+    /// Creates a new memfd of len bytes, sealed so its size can't be changed, and maps it.
+    /// This is what you want to share with another process: if the other process could shrink
+    /// the file, we'd get SIGBUS when accessing the buffer.
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    pub fn sealed_memfd(name: &str, len: usize) -> io::Result<Mmap> {
+        Mmap::new_memfd(name, len, ::libc::MFD_ALLOW_SEALING)
+    }
+
+    /// Like sealed_memfd, but backed by huge pages if possible, see huge_anonymous.
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    pub fn sealed_huge_memfd(name: &str, len: usize) -> io::Result<Mmap> {
+        Mmap::new_huge_memfd(name, len, ::libc::MFD_ALLOW_SEALING)
+    }
+
+    /// Maps the whole file, after checking that it has been sealed by sealed_memfd
+    /// (so that it can't shrink under our feet).
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    pub fn from_sealed_fd(fd: OwnedFd) -> io::Result<Mmap> {
+        let seals = try!(cvt(unsafe { ::libc::fcntl(fd.as_raw_fd(), ::libc::F_GET_SEALS) }));
+        if seals & SEALS != SEALS {
+            return Err(io::Error::new(io::ErrorKind::PermissionDenied, "The memfd is not sealed against resizing"));
+        }
+        Mmap::from_fd_all(fd)
+    }
+
+    /// The file being mapped.
+    pub fn fd(&self) -> Option<&OwnedFd> { self.fd.as_ref() }
+
+    /// True if the memory was allocated from huge pages by one of the huge_* constructors.
+    /// (A mapping of an fd from another process always returns false.)
+    pub fn is_huge(&self) -> bool { self.huge }
+}
+
+/// The default huge page size, or None if the system does not have huge pages.
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub fn huge_page_size() -> Option<usize> {
+    let s = match ::std::fs::read_to_string("/proc/meminfo") { Ok(s) => s, Err(_) => return None };
+    s.lines().filter_map(|l| l.strip_prefix("Hugepagesize:"))
+        .filter_map(|l| l.trim().trim_end_matches("kB").trim().parse::<usize>().ok())
+        .map(|kb| kb * 1024).next()
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn round_to_huge_page(len: usize) -> Option<usize> {
+    huge_page_size().map(|h| len.div_ceil(h) * h)
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+const SEALS: ::libc::c_int = ::libc::F_SEAL_SHRINK | ::libc::F_SEAL_GROW | ::libc::F_SEAL_SEAL;
+
+pub(crate) fn file_size(fd: &OwnedFd) -> io::Result<usize> {
+    let mut st: ::libc::stat = unsafe { ::std::mem::zeroed() };
+    try!(cvt(unsafe { ::libc::fstat(fd.as_raw_fd(), &mut st) }));
+    Ok(st.st_size as usize)
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub(crate) fn memfd_create(name: &str, flags: ::libc::c_uint) -> io::Result<OwnedFd> {
+    let name = try!(CString::new(name).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e)));
+    let fd = try!(cvt(unsafe { ::libc::memfd_create(name.as_ptr(), flags) }));
+    Ok(unsafe { OwnedFd::from_raw_fd(fd) })
+}
+
+impl Deref for Mmap {
+    type Target = [u8];
+    fn deref(&self) -> &[u8] { unsafe { ::std::slice::from_raw_parts(self.ptr, self.len) } }
+}
+
+impl DerefMut for Mmap {
+    fn deref_mut(&mut self) -> &mut [u8] { unsafe { ::std::slice::from_raw_parts_mut(self.ptr, self.len) } }
+}
+
+impl Drop for Mmap {
+    fn drop(&mut self) {
+        if unsafe { ::libc::munmap(self.ptr as *mut ::libc::c_void, self.len) } == -1 {
+            debug!("munmap failed: {}", io::Error::last_os_error());
+        }
+    }
+}
+
+/// Locks a buffer in memory (with mlock) and prefaults every page, so that using the ringbuf
+/// never causes a page fault. The buffer is unlocked when dropped.
+///
+/// Wrap the buffer before creating the channel, e g `ringbuf::channel(Locked::new(vec)?)`.
+/// The buffer must not move in memory, which holds for Vec, Box and Mmap.
+///
+/// Memory is locked in whole pages, and locks don't nest: unlocking the buffer also unlocks
+/// the rest of its first and last page, even if something else (e g another Locked buffer)
+/// has locked that memory. To avoid this, use a page aligned buffer that fills whole pages,
+/// such as an Mmap.
+pub struct Locked<U: DerefMut<Target=[u8]>> {
+    inner: U,
+}
+
+impl<U: DerefMut<Target=[u8]>> Locked<U> {
+    /// Fails if the memory could not be locked, typically because it would exceed RLIMIT_MEMLOCK.
+    /// Do this before anyone else uses the buffer: prefaulting writes to every page.
+    pub fn new(mut inner: U) -> io::Result<Locked<U>> {
+        {
+            let b: &mut [u8] = &mut inner;
+            if unsafe { ::libc::mlock(b.as_ptr() as *const ::libc::c_void, b.len()) } == -1 {
+                let e = io::Error::last_os_error();
+                return Err(io::Error::new(e.kind(), format!("Failed to lock {} bytes of memory (RLIMIT_MEMLOCK is {}): {}",
+                    b.len(), memlock_limit(), e)));
+            }
+            // mlock should fault in the pages, but make sure they're also writable without faulting.
+            // Touch one byte of every page, but only bytes within the buffer.
+            let page = unsafe { ::libc::sysconf(::libc::_SC_PAGESIZE) } as usize;
+            let (start, end) = (b.as_ptr() as usize, b.as_ptr() as usize + b.len());
+            for addr in (start & !(page - 1) .. end).step_by(page).filter(|_| end > start) {
+                let i = ::std::cmp::max(addr, start) - start;
+                unsafe { let p = b.as_mut_ptr().offset(i as isize); ::std::ptr::write_volatile(p, ::std::ptr::read_volatile(p)) };
+            }
+        }
+        Ok(Locked { inner: inner })
+    }
+}
+
+fn memlock_limit() -> String {
+    let mut r: ::libc::rlimit = unsafe { ::std::mem::zeroed() };
+    if unsafe { ::libc::getrlimit(::libc::RLIMIT_MEMLOCK, &mut r) } == -1 { return "unknown".into() };
+    if r.rlim_cur == ::libc::RLIM_INFINITY { "unlimited".into() } else { format!("{} bytes", r.rlim_cur) }
+}
+
+impl<U: DerefMut<Target=[u8]>> Deref for Locked<U> {
+    type Target = [u8];
+    fn deref(&self) -> &[u8] { &self.inner }
+}
+
+impl<U: DerefMut<Target=[u8]>> DerefMut for Locked<U> {
+    fn deref_mut(&mut self) -> &mut [u8] { &mut self.inner }
+}
+
+impl<U: DerefMut<Target=[u8]>> Drop for Locked<U> {
+    fn drop(&mut self) {
+        if unsafe { ::libc::munlock(self.inner.as_ptr() as *const ::libc::c_void, self.inner.len()) } == -1 {
+            debug!("munlock failed: {}", io::Error::last_os_error());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Mmap, Locked};
+    use std::os::unix::io::AsRawFd;
+
+    #[test]
+    fn sealed() {
+        let mut m = Mmap::sealed_memfd("test", 4096).unwrap();
+        m[4095] = 7;
+        let fd = m.fd().unwrap().as_raw_fd();
+        assert_eq!(unsafe { ::libc::ftruncate(fd, 100) }, -1);
+        let fd2 = m.fd().unwrap().try_clone().unwrap();
+        let m2 = Mmap::from_sealed_fd(fd2).unwrap();
+        assert_eq!(m2.len(), 4096);
+        assert_eq!(m2[4095], 7);
+
+        let m3 = Mmap::memfd("test", 4096).unwrap();
+        let e = Mmap::from_sealed_fd(m3.fd().unwrap().try_clone().unwrap()).err().unwrap();
+        assert_eq!(e.kind(), ::std::io::ErrorKind::PermissionDenied);
+    }
+
+    #[test]
+    fn huge() {
+        let len = ::channel_bufsize::<u64>(1000);
+        let m = Mmap::huge_anonymous(len).unwrap();
+        if m.is_huge() { assert_eq!(m.len() % super::huge_page_size().unwrap(), 0) }
+        else { assert_eq!(m.len(), len) }
+        let (mut s, mut r) = ::ringbuf::channel::<u64, _>(m);
+        s.send_foreach(2, |i| i as u64).unwrap();
+        r.recv(|d| { assert_eq!(d, &[0, 1]); 2 }).unwrap();
+
+        let f = Mmap::sealed_huge_memfd("test", len).unwrap();
+        assert!(f.len() >= len);
+        let f2 = Mmap::from_sealed_fd(f.fd().unwrap().try_clone().unwrap()).unwrap();
+        assert_eq!(f2.len(), f.len());
+    }
+
+    #[test]
+    fn locked() {
+        let q = Locked::new(vec![0u8; ::channel_bufsize::<u32>(5000)]).unwrap();
+        let (mut s, mut r) = ::fdbuf::channel_new::<u32, _>(q).unwrap();
+        s.send_foreach(3, |i| i as u32).unwrap();
+        r.recv(|d| { assert_eq!(d, &[0, 1, 2]); (3, false) }).unwrap();
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn memlock_limit() {
+        ::child::wait(::child::spawn("mmap::tests::memlock_limit_child").0);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    #[ignore]
+    fn memlock_limit_child() {
+        if ::child::socket().is_none() { return };
+        let r = ::libc::rlimit { rlim_cur: 4096, rlim_max: 4096 };
+        assert_eq!(unsafe { ::libc::setrlimit(::libc::RLIMIT_MEMLOCK, &r) }, 0);
+        // Root (or rather CAP_IPC_LOCK) ignores the limit.
+        if unsafe { ::libc::getuid() } == 0 { assert_eq!(unsafe { ::libc::setuid(65534) }, 0) };
+        let e = Locked::new(vec![0u8; 65536]).err().unwrap();
+        assert_eq!(e.kind(), ::std::io::ErrorKind::OutOfMemory);
+        assert!(e.to_string().contains("Failed to lock 65536 bytes of memory (RLIMIT_MEMLOCK is 4096 bytes)"), "{}", e);
+    }
+}
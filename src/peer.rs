@@ -0,0 +1,96 @@
+//! Identifies the process owning each side of the ringbuffer, so that the other
+//! side can find out if it has died (even if it didn't get a chance to close).
+
+use std::os::unix::io::RawFd;
+use std::io;
+
+/// A process id, plus the start time of that process (to protect against pid reuse).
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Identity {
+    pub pid: usize,
+    pub start: usize,
+}
+
+impl Identity {
+    pub fn current() -> Identity { Identity::of(unsafe { ::libc::getpid() } as usize) }
+
+    /// The start time is zero if the process does not exist (anymore).
+    pub fn of(pid: usize) -> Identity { Identity { pid: pid, start: start_time(pid).unwrap_or(0) } }
+
+    pub fn is_current(&self) -> bool { self.pid == unsafe { ::libc::getpid() } as usize }
+}
+
+/// Reads the start time of a process from /proc/<pid>/stat, field 22.
+/// Returns None if the process does not exist (or we're not on Linux).
+fn start_time(pid: usize) -> Option<usize> {
+    use std::fs::File;
+    use std::io::Read;
+
+    let mut s = String::new();
+    if File::open(format!("/proc/{}/stat", pid)).and_then(|mut f| f.read_to_string(&mut s)).is_err() { return None };
+    // The process name can contain spaces and parentheses, so start after the last ')'.
+    let fields = &s[s.rfind(')').map(|i| i + 1).unwrap_or(0)..];
+    fields.split_whitespace().nth(19).and_then(|t| t.parse().ok())
+}
+
+/// Owns a pidfd for the peer process. It becomes readable when the peer dies.
+pub struct PidFd(RawFd);
+
+impl PidFd {
+    /// Returns None if the process has already died (or has been replaced by another
+    /// process with the same pid).
+    #[cfg(target_os = "linux")]
+    pub fn open(id: Identity) -> io::Result<Option<PidFd>> {
+        let fd = unsafe { ::libc::syscall(::libc::SYS_pidfd_open, id.pid as ::libc::pid_t, 0) };
+        if fd == -1 {
+            let e = io::Error::last_os_error();
+            return if e.raw_os_error() == Some(::libc::ESRCH) { Ok(None) } else { Err(e) };
+        }
+        let p = PidFd(fd as RawFd);
+        // The pidfd refers to whatever process has the pid now, so check that it's the right one.
+        if id.start != 0 && start_time(id.pid) != Some(id.start) { return Ok(None) };
+        Ok(Some(p))
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    pub fn open(_: Identity) -> io::Result<Option<PidFd>> {
+        Err(io::Error::new(io::ErrorKind::Other, "pidfds are only supported on Linux"))
+    }
+
+    pub fn fd(&self) -> RawFd { self.0 }
+
+    /// Checks (without blocking) whether the process has exited.
+    pub fn is_dead(&self) -> io::Result<bool> {
+        let mut p = ::libc::pollfd { fd: self.0, events: ::libc::POLLIN, revents: 0 };
+        let e = unsafe { ::libc::poll(&mut p, 1, 0) };
+        if e == -1 { return Err(io::Error::last_os_error()) }
+        Ok(p.revents != 0)
+    }
+}
+
+impl Drop for PidFd {
+    fn drop(&mut self) { unsafe { ::libc::close(self.0) }; }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Identity, PidFd};
+
+    #[test]
+    fn pidfd_dead() {
+        let mut child = ::std::process::Command::new("sleep").arg("10").spawn().unwrap();
+        let pid = child.id() as usize;
+        let id = Identity { pid: pid, start: super::start_time(pid).unwrap() };
+        assert!(!id.is_current());
+        let p = PidFd::open(id).unwrap().unwrap();
+        assert!(!p.is_dead().unwrap());
+
+        // A process with the same pid, but another start time, is not the same process
+        assert!(PidFd::open(Identity { pid: pid, start: id.start + 1 }).unwrap().is_none());
+
+        child.kill().unwrap();
+        child.wait().unwrap();
+        assert!(p.is_dead().unwrap());
+        assert!(PidFd::open(id).unwrap().is_none());
+    }
+}
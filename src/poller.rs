@@ -0,0 +1,239 @@
+//! Waits for many fdbuf endpoints at once, using epoll.
+
+use std::io;
+use std::os::unix::io::{RawFd, OwnedFd, AsRawFd, FromRawFd};
+use std::time::{Duration, Instant};
+use fdbuf::{Sender, Receiver, Pipe, cvt, timeout_ms};
+use signal::Signal;
+
+/// An endpoint added to a Poller.
+pub enum Endpoint<T, U, S: Signal = Pipe> {
+    Sender(Sender<T, U, S>),
+    Receiver(Receiver<T, U, S>),
+}
+
+impl<T, U, S: Signal> Endpoint<T, U, S> {
+    fn wait_fd(&self) -> Option<RawFd> {
+        match *self { Endpoint::Sender(ref s) => s.wait_fd(), Endpoint::Receiver(ref r) => r.wait_fd() }
+    }
+
+    fn peer_fd(&mut self) -> io::Result<Option<RawFd>> {
+        match *self { Endpoint::Sender(ref mut s) => s.peer_fd(), Endpoint::Receiver(ref mut r) => r.peer_fd() }
+    }
+
+    fn peer_changed(&self) -> bool {
+        match *self { Endpoint::Sender(ref s) => s.peer_changed(), Endpoint::Receiver(ref r) => r.peer_changed() }
+    }
+
+    fn reopen_peer_fd(&mut self) -> io::Result<Option<RawFd>> {
+        match *self { Endpoint::Sender(ref mut s) => s.reopen_peer_fd(), Endpoint::Receiver(ref mut r) => r.reopen_peer_fd() }
+    }
+
+    /// Errors (i e, a dead peer) count as ready, so that the user finds out.
+    fn is_ready(&mut self) -> bool {
+        let r = match *self { Endpoint::Sender(ref mut s) => s.wait_ready(), Endpoint::Receiver(ref mut r) => r.wait_ready() };
+        r.map(|n| n > 0).unwrap_or(true)
+    }
+}
+
+struct Slot<T, U, S: Signal> {
+    endpoint: Endpoint<T, U, S>,
+    ready: bool,
+}
+
+impl<T, U, S: Signal> Slot<T, U, S> {
+    fn check(&mut self, epoll: RawFd, key: usize) {
+        // If the other side has been handed off, wait for the new process instead.
+        if self.endpoint.peer_changed() {
+            if let Ok(Some(p)) = self.endpoint.peer_fd() { let _ = epoll_ctl(epoll, ::libc::EPOLL_CTL_DEL, p, 0); }
+            match self.endpoint.reopen_peer_fd() {
+                Ok(Some(p)) => if let Err(e) = epoll_ctl(epoll, ::libc::EPOLL_CTL_ADD, p, ((key as u64) << 1) | 1) {
+                    debug!("Failed to register the new peer's pidfd: {}", e);
+                },
+                Ok(None) => {},
+                // The new peer is already gone, which is_ready reports.
+                Err(_) => {},
+            }
+        }
+        self.ready = self.endpoint.is_ready();
+    }
+}
+
+/// Owns an epoll instance and any number of senders and receivers, and waits until
+/// at least one of them can be written to or read from.
+///
+/// The wait fds are registered level triggered, together with the peer's pidfd
+/// if the peer is another process. Endpoints whose peer has died are reported as ready;
+/// call check_peer to find out. If the peer is handed off to another process, the Poller
+/// switches to the new process's pidfd.
+///
+/// All endpoints in a Poller have the same item type, buffer type and Signal. To wait for
+/// rings of different types in one loop, register them with mio instead (see `mio_source`),
+/// or add their `wait_fd` and `peer_fd` to your own event loop.
+pub struct Poller<T, U, S: Signal = Pipe> {
+    epoll: OwnedFd,
+    slots: Vec<Option<Slot<T, U, S>>>,
+    events: Vec<::libc::epoll_event>,
+}
+
+fn epoll_ctl(epoll: RawFd, op: ::libc::c_int, fd: RawFd, data: u64) -> io::Result<()> {
+    let mut ev = ::libc::epoll_event { events: ::libc::EPOLLIN as u32, u64: data };
+    cvt(unsafe { ::libc::epoll_ctl(epoll, op, fd, &mut ev) }).map(|_| ())
+}
+
+/// Returns the number of events, zero on timeout.
+fn epoll_wait(epoll: RawFd, events: &mut [::libc::epoll_event], deadline: Option<Instant>) -> io::Result<usize> {
+    loop {
+        let timeout = match timeout_ms(deadline) { Some(t) => t, None => return Ok(0) };
+        let e = unsafe { ::libc::epoll_wait(epoll, events.as_mut_ptr(), events.len() as ::libc::c_int, timeout) };
+        if e == -1 {
+            let err = io::Error::last_os_error();
+            if err.kind() == io::ErrorKind::Interrupted { continue };
+            return Err(err);
+        }
+        if e > 0 || deadline.is_some() { return Ok(e as usize) };
+    }
+}
+
+impl<T, U, S: Signal> Poller<T, U, S> {
+    pub fn new() -> io::Result<Poller<T, U, S>> {
+        let fd = try!(cvt(unsafe { ::libc::epoll_create1(::libc::EPOLL_CLOEXEC) }));
+        let epoll = unsafe { OwnedFd::from_raw_fd(fd) };
+        let ev = ::libc::epoll_event { events: 0, u64: 0 };
+        Ok(Poller { epoll: epoll, slots: vec![], events: vec![ev; 16] })
+    }
+
+    /// Adds an endpoint, and returns its key. The Signal must have an fd.
+    pub fn add(&mut self, mut endpoint: Endpoint<T, U, S>) -> io::Result<usize> {
+        let wait = try!(endpoint.wait_fd().ok_or(io::Error::new(io::ErrorKind::InvalidInput, "The Signal has no fd to wait for")));
+        let peer = try!(endpoint.peer_fd());
+        let key = self.slots.iter().position(|s| s.is_none()).unwrap_or(self.slots.len());
+        // The lowest bit tells the wait fd and the pidfd apart.
+        try!(epoll_ctl(self.epoll.as_raw_fd(), ::libc::EPOLL_CTL_ADD, wait, (key as u64) << 1));
+        if let Some(p) = peer {
+            if let Err(e) = epoll_ctl(self.epoll.as_raw_fd(), ::libc::EPOLL_CTL_ADD, p, ((key as u64) << 1) | 1) {
+                let _ = epoll_ctl(self.epoll.as_raw_fd(), ::libc::EPOLL_CTL_DEL, wait, 0);
+                return Err(e);
+            }
+        }
+        // Checked (and armed) on the next wait.
+        let slot = Some(Slot { endpoint: endpoint, ready: true });
+        if key == self.slots.len() { self.slots.push(slot) } else { self.slots[key] = slot };
+        Ok(key)
+    }
+
+    pub fn add_sender(&mut self, s: Sender<T, U, S>) -> io::Result<usize> { self.add(Endpoint::Sender(s)) }
+
+    pub fn add_receiver(&mut self, r: Receiver<T, U, S>) -> io::Result<usize> { self.add(Endpoint::Receiver(r)) }
+
+    /// Removes an endpoint and gives it back. The key might be reused by later calls to add.
+    pub fn remove(&mut self, key: usize) -> Option<Endpoint<T, U, S>> {
+        let mut slot = self.slots.get_mut(key).and_then(|s| s.take())?;
+        let epoll = self.epoll.as_raw_fd();
+        if let Some(w) = slot.endpoint.wait_fd() { let _ = epoll_ctl(epoll, ::libc::EPOLL_CTL_DEL, w, 0); }
+        if let Ok(Some(p)) = slot.endpoint.peer_fd() { let _ = epoll_ctl(epoll, ::libc::EPOLL_CTL_DEL, p, 0); }
+        Some(slot.endpoint)
+    }
+
+    pub fn get_mut(&mut self, key: usize) -> Option<&mut Endpoint<T, U, S>> {
+        self.slots.get_mut(key).and_then(|s| s.as_mut()).map(|s| &mut s.endpoint)
+    }
+
+    /// Waits until at least one endpoint is ready, and returns the ready endpoints with their keys.
+    /// Returns no endpoints if the timeout passes first.
+    ///
+    /// Endpoints returned are checked again on the next call, so there's no need to
+    /// read or write everything before calling wait again, and wait_clear is done by the Poller.
+    pub fn wait<'a>(&'a mut self, timeout: Option<Duration>) -> io::Result<impl Iterator<Item=(usize, &'a mut Endpoint<T, U, S>)> + 'a> {
+        let deadline = timeout.map(|t| Instant::now() + t);
+        let epoll = self.epoll.as_raw_fd();
+        let mut any = false;
+        for (key, slot) in self.slots.iter_mut().enumerate().filter_map(|(k, s)| s.as_mut().map(|s| (k, s))) {
+            if slot.ready { slot.check(epoll, key) };
+            any |= slot.ready;
+        }
+        while !any {
+            let n = try!(epoll_wait(epoll, &mut self.events, deadline));
+            if n == 0 { break };
+            for ev in &self.events[..n] {
+                let key = (ev.u64 >> 1) as usize;
+                if let Some(&mut Some(ref mut slot)) = self.slots.get_mut(key) {
+                    slot.check(epoll, key);
+                    any |= slot.ready;
+                }
+            }
+        }
+        Ok(self.slots.iter_mut().enumerate().filter_map(|(k, s)| match *s {
+            Some(ref mut s) if s.ready => Some((k, &mut s.endpoint)),
+            _ => None,
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Poller, Endpoint};
+    use std::time::Duration;
+
+    #[test]
+    fn peer_handed_off() {
+        let mut poller = Poller::new().unwrap();
+        let q = vec![0u8; ::channel_bufsize::<u32>(2)];
+        let (mut s, _r) = ::fdbuf::channel_new::<u32, _>(q).unwrap();
+        s.send_foreach(2, |i| i as u32).unwrap();
+        let key = poller.add_sender(s).unwrap();
+
+        // As if the receiver had been handed off to another process, which then dies.
+        let mut child = ::std::process::Command::new("sleep").arg("10").spawn().unwrap();
+        match *poller.get_mut(key).unwrap() {
+            Endpoint::Sender(ref s) => s.set_peer(::peer::Identity::of(child.id() as usize)),
+            _ => unreachable!(),
+        }
+        assert_eq!(poller.wait(Some(Duration::from_millis(10))).unwrap().count(), 0);
+        child.kill().unwrap();
+        child.wait().unwrap();
+        let ready: Vec<_> = poller.wait(Some(Duration::from_secs(5))).unwrap().map(|(k, _)| k).collect();
+        assert_eq!(ready, vec![key]);
+        match poller.remove(key).unwrap() {
+            Endpoint::Sender(mut s) => assert_eq!(s.check_peer().unwrap_err().kind(), ::std::io::ErrorKind::ConnectionAborted),
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn poll_receivers() {
+        let mut poller = Poller::new().unwrap();
+        let mut threads = vec![];
+        for i in 0..4u32 {
+            let q = vec![0u8; ::channel_bufsize::<u32>(2)];
+            let (mut s, r) = ::fdbuf::channel_new::<u32, _>(q).unwrap();
+            assert_eq!(poller.add_receiver(r).unwrap(), i as usize);
+            threads.push(::std::thread::spawn(move || {
+                ::std::thread::sleep(Duration::from_millis(10 * i as u64));
+                for j in 0..5 { s.send_blocking(|p, _| { unsafe { *p = i * 10 + j }; (1, false) }).unwrap(); }
+            }));
+        }
+
+        let mut received = vec![vec![]; 4];
+        let mut done = 0;
+        while done < 4 {
+            let mut closed = vec![];
+            for (key, e) in poller.wait(Some(Duration::from_secs(5))).unwrap() {
+                let r = match *e { Endpoint::Receiver(ref mut r) => r, _ => unreachable!() };
+                match r.recv(|d| { received[key].extend_from_slice(d); (d.len(), false) }) {
+                    Ok(_) => {},
+                    Err(ref e) if e.kind() == ::std::io::ErrorKind::BrokenPipe => closed.push(key),
+                    Err(e) => panic!("{}", e),
+                }
+            }
+            for key in closed { assert!(poller.remove(key).is_some()); done += 1; }
+        }
+        for (i, v) in received.iter().enumerate() {
+            assert_eq!(*v, (0..5).map(|j| i as u32 * 10 + j).collect::<Vec<_>>());
+        }
+        for t in threads { t.join().unwrap(); }
+
+        // Nothing left, so this times out
+        assert_eq!(poller.wait(Some(Duration::from_millis(10))).unwrap().count(), 0);
+    }
+}
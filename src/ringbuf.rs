@@ -1,64 +1,349 @@
 //! This is a fast ringbuffer that tries to avoid memory copies as much as possible.
 //! There can be one producer and one consumer, but they can be in different threads
 //! i e, they are Send but not Clone.
+//!
+//! Within a process, async code can wait for the other side with `poll_write_count`
+//! and `poll_read_count`, which wake up the task directly (no fds needed).
 
-use std::sync::Arc;
-use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, Waker};
+use std::sync::atomic::{AtomicUsize, AtomicU32, Ordering};
 use std::mem::size_of;
 use std::ops::DerefMut;
+use std::{error, fmt, io};
+use peer::Identity;
+
+const SENDER_CLOSED: usize = 1;
+const RECEIVER_CLOSED: usize = 2;
+const SENDER_SLEEPING: usize = 4;
+const RECEIVER_SLEEPING: usize = 8;
+const SENDER_ATTACHED: usize = 16;
+const RECEIVER_ATTACHED: usize = 32;
+
+/// Set in count (as well as in flags) when either side is closed, so that send and recv
+/// notice without loading the flags.
+const COUNT_CLOSED: usize = !(!0 >> 1);
+
+/// Identifies an initialized ringbuffer (of this version).
+const MAGIC: usize = 0x6664_7262;
+
+/// This is what's stored in the beginning of the buffer, before the items.
+#[repr(C)]
+struct Header {
+    /// These three are written by init, and checked when attaching.
+    magic: usize,
+    item_size: usize,
+    capacity: usize,
+    /// Number of items in the buffer, with COUNT_CLOSED set once either side is closed.
+    count: AtomicUsize,
+    flags: AtomicUsize,
+    /// Increased every time the buffer is (re)initialized.
+    generation: AtomicUsize,
+    sender: Owner,
+    receiver: Owner,
+    /// For waking up the receiver and sender, see fdbuf::futex_channel.
+    empty_futex: AtomicU32,
+    full_futex: AtomicU32,
+}
+
+/// The process currently owning one side of the buffer.
+#[repr(C)]
+struct Owner {
+    pid: AtomicUsize,
+    start: AtomicUsize,
+}
+
+impl Owner {
+    fn store(&self, id: Identity) {
+        self.start.store(id.start, Ordering::Relaxed);
+        self.pid.store(id.pid, Ordering::Release);
+    }
+
+    fn load(&self) -> Option<Identity> {
+        let pid = self.pid.load(Ordering::Acquire);
+        if pid == 0 { return None };
+        Some(Identity { pid: pid, start: self.start.load(Ordering::Relaxed) })
+    }
+}
 
 #[allow(raw_pointer_derive)]
 #[derive(Copy, Clone)]
 struct Buf<T> {
     data: *mut T,
-    count_ptr: *const AtomicUsize,
+    header: *const Header,
     length: usize,
 }
 
 unsafe impl<T> Send for Buf<T> {}
 
+/// The tasks waiting for each side of an in-process channel, see `Receiver::poll_read_count`.
+#[derive(Default)]
+struct Wakers {
+    sender: Mutex<Option<Waker>>,
+    receiver: Mutex<Option<Waker>>,
+}
+
+/// Wakes up the other side if it's sleeping in poll_read_count/poll_write_count. If it's sleeping
+/// without a registered task (i e, waiting some other way, see wake_needed), the flag is left alone.
+fn wake<T>(buf: &Buf<T>, flag: usize, w: &Mutex<Option<Waker>>) {
+    // SeqCst, see take_flag.
+    if buf.flags().load(Ordering::SeqCst) & flag == 0 { return };
+    let w = {
+        let mut g = w.lock().unwrap();
+        if g.is_some() && buf.take_flag(flag) { g.take() } else { None }
+    };
+    if let Some(w) = w { w.wake() };
+}
+
 pub struct Sender<T, U> {
     buf: Buf<T>,
     index: usize,
+    generation: usize,
+    /// Handed over to someone else, so don't close on drop.
+    detached: bool,
+    wakers: Option<Arc<Wakers>>,
     _owner: Arc<U>,
 }
 
 pub struct Receiver<T, U> {
     buf: Buf<T>,
     index: usize,
+    generation: usize,
+    /// Handed over to someone else, so don't close on drop.
+    detached: bool,
+    wakers: Option<Arc<Wakers>>,
     _owner: Arc<U>,
 }
 
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Error {
+    /// The other side has been closed (or dropped). For the receiver, this is
+    /// only reported after all remaining items have been read.
+    Disconnected,
+    /// The process owning the other side has died.
+    PeerDied,
+    /// The buffer has been reinitialized (by calling `channel` again on the same memory)
+    /// since this side was created.
+    Reset,
+    /// The buffer has not been initialized, or was initialized for another item type.
+    Invalid,
+    /// That side of the buffer has already been attached.
+    InUse,
+    /// The buffer is full, e g when sending through the Sink without waiting for poll_ready.
+    Full,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::Disconnected => write!(f, "The other side of the ringbuffer has been closed"),
+            Error::PeerDied => write!(f, "The process owning the other side of the ringbuffer has died"),
+            Error::Reset => write!(f, "The ringbuffer has been reinitialized"),
+            Error::Invalid => write!(f, "The buffer does not contain a ringbuffer of this type"),
+            Error::InUse => write!(f, "That side of the ringbuffer is already in use"),
+            Error::Full => write!(f, "The ringbuffer is full"),
+        }
+    }
+}
+
+impl error::Error for Error {
+    fn description(&self) -> &str { "ringbuffer error" }
+}
+
+impl From<Error> for io::Error {
+    fn from(e: Error) -> io::Error {
+        let kind = match e {
+            Error::Disconnected => io::ErrorKind::BrokenPipe,
+            Error::PeerDied => io::ErrorKind::ConnectionAborted,
+            Error::Reset => io::ErrorKind::ConnectionReset,
+            Error::Invalid => io::ErrorKind::InvalidData,
+            Error::InUse => io::ErrorKind::AddrInUse,
+            Error::Full => io::ErrorKind::WouldBlock,
+        };
+        io::Error::new(kind, e)
+    }
+}
+
 /// Use this utility function to figure out how big buffer you need to allocate.
-pub fn channel_bufsize<T>(capacity: usize) -> usize { capacity * size_of::<T>() + size_of::<AtomicUsize>() }
+pub fn channel_bufsize<T>(capacity: usize) -> usize { capacity * size_of::<T>() + header_size() }
+
+/// The number of bytes in front of the items. If you size buffers by hand, add this
+/// to the space for the items (or use `channel_bufsize`).
+pub fn header_size() -> usize { size_of::<Header>() }
 
 
 /// Create a channel (without signaling)
 /// Non-allocating - expects a pre-allocated buffer
+///
+/// As both sides are in this process, async tasks can wait for each other, see `Receiver::poll_read_count`.
 pub fn channel<T: Send + Copy, U: Send + DerefMut<Target=[u8]>>(buffer: U) -> (Sender<T, U>, Receiver<T, U>) {
+    let (mut s, mut r) = raw_channel(buffer);
+    let w = Arc::new(Wakers::default());
+    s.wakers = Some(w.clone());
+    r.wakers = Some(w);
+    (s, r)
+}
 
+/// Like channel, but without waking up tasks, for fdbuf (which uses the sleeping flags itself).
+pub(crate) fn raw_channel<T: Send + Copy, U: Send + DerefMut<Target=[u8]>>(buffer: U) -> (Sender<T, U>, Receiver<T, U>) {
     let mut mem = buffer;
-    let b = {
-        let slice: &mut [u8] = &mut mem;
-        assert!(slice.len() >= size_of::<AtomicUsize>() + size_of::<T>(), "Buffer too small");
-
-        Buf {
-            count_ptr: slice.as_ptr() as *const AtomicUsize,
-            data: unsafe { slice.as_ptr().offset(size_of::<AtomicUsize>() as isize) } as *mut T,
-            length: (slice.len() - size_of::<AtomicUsize>()) / size_of::<T>(),
-        }
-    };
-    b.count().store(0, Ordering::Relaxed);
+    init::<T>(&mut mem);
+    let b = Buf::<T>::new(&mut mem).unwrap();
+    let g = b.attach(SENDER_ATTACHED | RECEIVER_ATTACHED).unwrap();
 
     let o = Arc::new(mem);
-    let s = Sender { buf: b, index: 0, _owner: o.clone() };
-    let r = Receiver { buf: b, index: 0, _owner: o };
+    let s = Sender { buf: b, index: 0, generation: g, detached: false, wakers: None, _owner: o.clone() };
+    let r = Receiver { buf: b, index: 0, generation: g, detached: false, wakers: None, _owner: o };
     (s, r)
 }
 
+/// Initializes the buffer, without creating a sender or receiver. Use `attach_sender` and
+/// `attach_receiver` for that, e g in two different processes sharing the buffer.
+pub fn init<T>(buffer: &mut [u8]) {
+    assert!(buffer.len() >= size_of::<Header>() + size_of::<T>(), "Buffer too small");
+    let h = unsafe { &mut *(buffer.as_mut_ptr() as *mut Header) };
+    h.magic = MAGIC;
+    h.item_size = size_of::<T>();
+    h.capacity = (buffer.len() - size_of::<Header>()) / size_of::<T>();
+    h.count.store(0, Ordering::Relaxed);
+    h.flags.store(0, Ordering::Relaxed);
+    h.sender.store(Identity { pid: 0, start: 0 });
+    h.receiver.store(Identity { pid: 0, start: 0 });
+    h.empty_futex.store(0, Ordering::Relaxed);
+    h.full_futex.store(0, Ordering::Relaxed);
+    // Any Sender or Receiver still attached to the old buffer will get Error::Reset.
+    h.generation.fetch_add(1, Ordering::SeqCst);
+}
+
+/// Creates the sender for a buffer initialized with `init`.
+/// Returns Error::Invalid if the buffer was initialized for another type (or not at all),
+/// and Error::InUse if a sender has already been attached.
+pub fn attach_sender<T: Send + Copy, U: Send + DerefMut<Target=[u8]>>(buffer: U) -> Result<Sender<T, U>, Error> {
+    let mut mem = buffer;
+    let b = try!(Buf::<T>::new(&mut mem));
+    let g = try!(b.attach(SENDER_ATTACHED));
+    Ok(Sender { buf: b, index: 0, generation: g, detached: false, wakers: None, _owner: Arc::new(mem) })
+}
+
+/// Creates the receiver for a buffer initialized with `init`. See `attach_sender`.
+pub fn attach_receiver<T: Send + Copy, U: Send + DerefMut<Target=[u8]>>(buffer: U) -> Result<Receiver<T, U>, Error> {
+    let mut mem = buffer;
+    let b = try!(Buf::<T>::new(&mut mem));
+    let g = try!(b.attach(RECEIVER_ATTACHED));
+    Ok(Receiver { buf: b, index: 0, generation: g, detached: false, wakers: None, _owner: Arc::new(mem) })
+}
+
+/// Takes over a sender given up with `Sender::detach` (typically in another process),
+/// continuing where it left off.
+pub(crate) fn resume_sender<T: Send + Copy, U: Send + DerefMut<Target=[u8]>>(buffer: U, index: usize, generation: usize) -> Result<Sender<T, U>, Error> {
+    let mut mem = buffer;
+    let b = try!(Buf::<T>::new(&mut mem));
+    try!(b.resume(SENDER_ATTACHED, index, generation));
+    Ok(Sender { buf: b, index: index, generation: generation, detached: false, wakers: None, _owner: Arc::new(mem) })
+}
+
+/// Checks that a sender (or receiver) could be resumed at this position, without taking it over.
+pub(crate) fn check_resume<T>(buffer: &mut [u8], sender: bool, index: usize, generation: usize) -> Result<(), Error> {
+    let b = try!(Buf::<T>::new(buffer));
+    b.check_resume(if sender { SENDER_ATTACHED } else { RECEIVER_ATTACHED }, index, generation)
+}
+
+/// Takes over a receiver given up with `Receiver::detach`. See `resume_sender`.
+pub(crate) fn resume_receiver<T: Send + Copy, U: Send + DerefMut<Target=[u8]>>(buffer: U, index: usize, generation: usize) -> Result<Receiver<T, U>, Error> {
+    let mut mem = buffer;
+    let b = try!(Buf::<T>::new(&mut mem));
+    try!(b.resume(RECEIVER_ATTACHED, index, generation));
+    Ok(Receiver { buf: b, index: index, generation: generation, detached: false, wakers: None, _owner: Arc::new(mem) })
+}
+
 impl<T> Buf<T> {
+    /// Checks that the buffer has been initialized for T, and fits the items.
+    fn new(slice: &mut [u8]) -> Result<Buf<T>, Error> {
+        if slice.len() < size_of::<Header>() { return Err(Error::Invalid) };
+        let h = unsafe { &*(slice.as_ptr() as *const Header) };
+        if h.magic != MAGIC || h.item_size != size_of::<T>() || h.capacity == 0 ||
+            slice.len() < channel_bufsize::<T>(h.capacity) { return Err(Error::Invalid) };
+        Ok(Buf {
+            header: h,
+            data: unsafe { slice.as_mut_ptr().offset(size_of::<Header>() as isize) } as *mut T,
+            length: h.capacity,
+        })
+    }
+
+    /// Claims one (or both) sides of the buffer, and returns the generation.
+    fn attach(&self, side: usize) -> Result<usize, Error> {
+        let g = self.header().generation.load(Ordering::SeqCst);
+        if self.flags().fetch_or(side, Ordering::SeqCst) & side != 0 { return Err(Error::InUse) };
+        let id = Identity::current();
+        if side & SENDER_ATTACHED != 0 { self.header().sender.store(id) };
+        if side & RECEIVER_ATTACHED != 0 { self.header().receiver.store(id) };
+        Ok(g)
+    }
+
+    fn check_resume(&self, side: usize, index: usize, generation: usize) -> Result<(), Error> {
+        try!(self.check_generation(generation));
+        if self.flags().load(Ordering::SeqCst) & side == 0 || index >= self.length { return Err(Error::Invalid) };
+        Ok(())
+    }
+
+    /// Records us as the owner of a side that has already been attached (by someone else).
+    fn resume(&self, side: usize, index: usize, generation: usize) -> Result<(), Error> {
+        try!(self.check_resume(side, index, generation));
+        self.set_owner(side, Identity::current());
+        Ok(())
+    }
+
+    fn set_owner(&self, side: usize, id: Identity) {
+        if side == SENDER_ATTACHED { self.header().sender.store(id) } else { self.header().receiver.store(id) };
+    }
+
+    #[inline]
+    fn header(&self) -> &Header { unsafe { &*self.header }}
+
     #[inline]
-    fn count(&self) -> &AtomicUsize { unsafe { &*self.count_ptr }}
+    fn count(&self) -> &AtomicUsize { &self.header().count }
+
+    #[inline]
+    fn flags(&self) -> &AtomicUsize { &self.header().flags }
+
+    #[inline]
+    fn check_generation(&self, g: usize) -> Result<(), Error> {
+        if self.header().generation.load(Ordering::Acquire) == g { Ok(()) } else { Err(Error::Reset) }
+    }
+
+    #[inline]
+    fn close(&self, flag: usize, g: usize) {
+        // Don't close a buffer that someone else has reinitialized.
+        if self.check_generation(g).is_ok() {
+            self.flags().fetch_or(flag, Ordering::SeqCst);
+            self.count().fetch_or(COUNT_CLOSED, Ordering::SeqCst);
+        }
+    }
+
+    #[inline]
+    fn is_closed(&self, flag: usize) -> bool { self.flags().load(Ordering::SeqCst) & flag != 0 }
+
+    /// Sets our sleeping flag, then returns number of items as seen afterwards.
+    /// (The other side does it the other way around, so at least one of us will notice.)
+    fn prepare_sleep(&self, flag: usize, closed: usize) -> Result<usize, Error> {
+        if self.flags().fetch_or(flag, Ordering::SeqCst) & closed != 0 { return Err(Error::Disconnected) }
+        Ok(self.count().load(Ordering::SeqCst) & !COUNT_CLOSED)
+    }
+
+    #[inline]
+    fn item_count(&self) -> usize { self.count().load(Ordering::Relaxed) & !COUNT_CLOSED }
+
+    /// Returns true if the flag was set (and clears it).
+    ///
+    /// The load must be SeqCst: we have just changed the count, and the sleeping side has just
+    /// set its flag and is about to read the count (see prepare_sleep). Each side writes one word
+    /// and then reads the other, and with anything weaker than SeqCst on both the write and
+    /// the read, both sides may see the old value and the wakeup is lost.
+    #[inline]
+    fn take_flag(&self, flag: usize) -> bool {
+        self.flags().load(Ordering::SeqCst) & flag != 0 &&
+            self.flags().fetch_and(!flag, Ordering::SeqCst) & flag != 0
+    }
 
     #[inline]
     fn slice(&mut self) -> &mut [T] {
@@ -82,10 +367,14 @@ impl<T, U> Sender<T, U> {
     ///
     /// Since this is a ringbuffer, there might be more items to write even if you
     /// completely fill up during the closure.
-    pub fn send<F: FnOnce(*mut T, usize) -> usize>(&mut self, f: F) -> (usize, bool) {
+    ///
+    /// Returns Error::Disconnected (without calling the closure) if either side has been closed.
+    pub fn send<F: FnOnce(*mut T, usize) -> usize>(&mut self, f: F) -> Result<(usize, bool), Error> {
         use std::cmp;
 
+        try!(self.buf.check_generation(self.generation));
         let cb = self.buf.count().load(Ordering::SeqCst);
+        if cb & COUNT_CLOSED != 0 { return Err(Error::Disconnected) }
         let l = self.buf.length;
 
         let n = {
@@ -99,17 +388,18 @@ impl<T, U> Sender<T, U> {
              n
         };
 
-        let c = self.buf.count().fetch_add(n, Ordering::SeqCst);
+        let c = self.buf.count().fetch_add(n, Ordering::SeqCst) & !COUNT_CLOSED;
         self.index = (self.index + n) % l;
         trace!("Send: cb = {}, c = {}, l = {}, n = {}", cb, c, l, n);
-        (l - c - n, c == 0 && n > 0)
+        if n > 0 { self.wake_receiver() };
+        Ok((l - c - n, c == 0 && n > 0))
     }
 
     /// "Safe" version of send. Will call your closure up to "count" times
     /// and depend on RVO to avoid memory copies.
     ///
     /// Returns (free items, was empty) like send does
-    pub fn send_foreach<F: FnMut(usize) -> T>(&mut self, count: usize, mut f: F) -> (usize, bool) {
+    pub fn send_foreach<F: FnMut(usize) -> T>(&mut self, count: usize, mut f: F) -> Result<(usize, bool), Error> {
         use std::ptr;
 
         let mut i = 0;
@@ -123,7 +413,92 @@ impl<T, U> Sender<T, U> {
     }
 
     /// Returns number of items that can be written
-    pub fn write_count(&self) -> usize { self.buf.length - self.buf.count().load(Ordering::Relaxed) }
+    pub fn write_count(&self) -> usize { self.buf.length - self.buf.item_count() }
+
+    /// Closes the sender. The receiver can still read the remaining items,
+    /// after that it will get Error::Disconnected.
+    /// This is also done automatically when the sender is dropped.
+    pub fn close(&mut self) {
+        self.buf.close(SENDER_CLOSED, self.generation);
+        self.wake_receiver();
+    }
+
+    /// Returns true if the receiver has been closed (or dropped).
+    pub fn is_disconnected(&self) -> bool { self.buf.is_closed(RECEIVER_CLOSED) }
+
+    /// Tells the receiver that we're about to sleep until it has read something.
+    /// Returns number of items that can be written; you should only sleep if it is zero.
+    /// Returns Error::Disconnected if the receiver has been closed.
+    pub fn prepare_sleep(&self) -> Result<usize, Error> {
+        self.buf.prepare_sleep(SENDER_SLEEPING, RECEIVER_CLOSED).map(|c| self.buf.length - c)
+    }
+
+    /// Takes back a prepare_sleep. Returns false if the receiver has already
+    /// seen that we're sleeping, i e, a wakeup is on its way.
+    pub fn cancel_sleep(&self) -> bool { self.buf.take_flag(SENDER_SLEEPING) }
+
+    /// Returns true if the receiver is sleeping, and should be woken up.
+    /// This also clears the receiver's sleeping flag, so only one wakeup is needed.
+    pub fn wake_needed(&self) -> bool { self.buf.take_flag(RECEIVER_SLEEPING) }
+
+    /// For async code, with channels created by `channel`: returns the number of items that can
+    /// be written, or Pending if there are none, in which case the task is woken up when the
+    /// receiver has read something (or has been closed).
+    ///
+    /// Returns Error::Disconnected if the receiver has been closed, and Error::Invalid if the
+    /// receiver is not in this process (e g an attached buffer).
+    pub fn poll_write_count(&mut self, cx: &mut Context) -> Poll<Result<usize, Error>> {
+        if let Err(e) = self.buf.check_generation(self.generation) { return Poll::Ready(Err(e)) };
+        if self.is_disconnected() { return Poll::Ready(Err(Error::Disconnected)) };
+        let n = self.write_count();
+        if n > 0 { return Poll::Ready(Ok(n)) };
+        let w = match self.wakers {
+            Some(ref w) => &w.sender,
+            None => return Poll::Ready(Err(Error::Invalid)),
+        };
+        *w.lock().unwrap() = Some(cx.waker().clone());
+        match self.prepare_sleep() {
+            Ok(0) => Poll::Pending,
+            r => { self.cancel_sleep(); w.lock().unwrap().take(); Poll::Ready(r) },
+        }
+    }
+
+    #[inline]
+    fn wake_receiver(&self) {
+        if let Some(ref w) = self.wakers { wake(&self.buf, RECEIVER_SLEEPING, &w.receiver) }
+    }
+
+    /// The process owning the receiver, if known.
+    pub(crate) fn peer(&self) -> Option<Identity> { self.buf.header().receiver.load() }
+
+    /// Records the process owning the receiver, before it has attached itself.
+    pub(crate) fn set_peer(&self, id: Identity) { self.buf.header().receiver.store(id) }
+
+    /// Gives up the sender without closing it, so that someone else can take over with
+    /// `resume_sender` (using the index and generation from `position`).
+    pub(crate) fn detach(&mut self) { self.detached = true }
+
+    pub(crate) fn is_detached(&self) -> bool { self.detached }
+
+    /// The (index, generation) to resume with.
+    pub(crate) fn position(&self) -> (usize, usize) { (self.index, self.generation) }
+
+    /// Records us as the owner again, e g if someone else took over and then failed.
+    pub(crate) fn reclaim(&self) -> Result<(), Error> { self.buf.resume(SENDER_ATTACHED, self.index, self.generation) }
+
+    /// Records the process taking over the sender, see `detach`.
+    pub(crate) fn set_owner(&self, id: Identity) { self.buf.set_owner(SENDER_ATTACHED, id) }
+
+    pub(crate) fn owner(&self) -> &U { &self._owner }
+
+    /// The (empty, full) futex words in the header.
+    pub(crate) fn futexes(&self) -> (&AtomicU32, &AtomicU32) {
+        (&self.buf.header().empty_futex, &self.buf.header().full_futex)
+    }
+}
+
+impl<T, U> Drop for Sender<T, U> {
+    fn drop(&mut self) { if !self.detached { self.close() } }
 }
 
 impl<T, U> Receiver<T, U> {
@@ -133,10 +508,19 @@ impl<T, U> Receiver<T, U> {
     /// f: This closure returns number of items that can be dropped from buffer.
     /// Since this is a ringbuffer, there might be more items to read even if you
     /// read it all during the closure.
-    pub fn recv<F: FnOnce(&[T]) -> usize>(&mut self, f: F) -> (usize, bool) {
+    ///
+    /// Returns Error::Disconnected (without calling the closure) if the buffer is empty
+    /// and either side has been closed.
+    pub fn recv<F: FnOnce(&[T]) -> usize>(&mut self, f: F) -> Result<(usize, bool), Error> {
         use std::cmp;
 
-        let cb = self.buf.count().load(Ordering::SeqCst);
+        try!(self.buf.check_generation(self.generation));
+        let mut cb = self.buf.count().load(Ordering::SeqCst);
+        if cb & COUNT_CLOSED != 0 {
+            // Items written before closing are still counted.
+            cb &= !COUNT_CLOSED;
+            if cb == 0 { return Err(Error::Disconnected) }
+        }
         let l = self.buf.length;
         let n = {
             let data: &[T] = self.buf.slice();
@@ -147,14 +531,98 @@ impl<T, U> Receiver<T, U> {
             n
         };
 
-        let c = self.buf.count().fetch_sub(n, Ordering::SeqCst);
+        let c = self.buf.count().fetch_sub(n, Ordering::SeqCst) & !COUNT_CLOSED;
         self.index = (self.index + n) % l;
         trace!("Recv: cb = {}, c = {}, l = {}, n = {}", cb, c, l, n);
-        return (c - n, c >= l && n > 0)
+        if n > 0 { self.wake_sender() };
+        return Ok((c - n, c >= l && n > 0))
     }
 
     /// Returns number of items that can be read
-    pub fn read_count(&self) -> usize { self.buf.count().load(Ordering::Relaxed) }
+    pub fn read_count(&self) -> usize { self.buf.item_count() }
+
+    /// Closes the receiver. The sender will get Error::Disconnected on the next send.
+    /// This is also done automatically when the receiver is dropped.
+    pub fn close(&mut self) {
+        self.buf.close(RECEIVER_CLOSED, self.generation);
+        self.wake_sender();
+    }
+
+    /// Returns true if the sender has been closed (or dropped).
+    /// There might still be items left to read.
+    pub fn is_disconnected(&self) -> bool { self.buf.is_closed(SENDER_CLOSED) }
+
+    /// Tells the sender that we're about to sleep until it has written something.
+    /// Returns number of items that can be read; you should only sleep if it is zero.
+    /// Returns Error::Disconnected if the sender has been closed and there is nothing left to read.
+    pub fn prepare_sleep(&self) -> Result<usize, Error> {
+        match self.buf.prepare_sleep(RECEIVER_SLEEPING, SENDER_CLOSED) {
+            Err(_) if self.read_count() > 0 => Ok(self.read_count()),
+            x => x,
+        }
+    }
+
+    /// Takes back a prepare_sleep. Returns false if the sender has already
+    /// seen that we're sleeping, i e, a wakeup is on its way.
+    pub fn cancel_sleep(&self) -> bool { self.buf.take_flag(RECEIVER_SLEEPING) }
+
+    /// Returns true if the sender is sleeping, and should be woken up.
+    /// This also clears the sender's sleeping flag, so only one wakeup is needed.
+    pub fn wake_needed(&self) -> bool { self.buf.take_flag(SENDER_SLEEPING) }
+
+    /// For async code, with channels created by `channel`: returns the number of items that can
+    /// be read, or Pending if there are none, in which case the task is woken up when the
+    /// sender has written something (or has been closed).
+    ///
+    /// Returns Error::Disconnected if the sender has been closed and there is nothing left to read,
+    /// and Error::Invalid if the sender is not in this process.
+    pub fn poll_read_count(&mut self, cx: &mut Context) -> Poll<Result<usize, Error>> {
+        if let Err(e) = self.buf.check_generation(self.generation) { return Poll::Ready(Err(e)) };
+        let n = self.read_count();
+        if n > 0 { return Poll::Ready(Ok(n)) };
+        let w = match self.wakers {
+            Some(ref w) => &w.receiver,
+            None => return Poll::Ready(Err(Error::Invalid)),
+        };
+        *w.lock().unwrap() = Some(cx.waker().clone());
+        match self.prepare_sleep() {
+            Ok(0) => Poll::Pending,
+            r => { self.cancel_sleep(); w.lock().unwrap().take(); Poll::Ready(r) },
+        }
+    }
+
+    #[inline]
+    fn wake_sender(&self) {
+        if let Some(ref w) = self.wakers { wake(&self.buf, SENDER_SLEEPING, &w.sender) }
+    }
+
+    /// The process owning the sender, if known.
+    pub(crate) fn peer(&self) -> Option<Identity> { self.buf.header().sender.load() }
+
+    /// Records the process owning the sender, before it has attached itself.
+    pub(crate) fn set_peer(&self, id: Identity) { self.buf.header().sender.store(id) }
+
+    /// Gives up the receiver without closing it, see `Sender::detach`.
+    pub(crate) fn detach(&mut self) { self.detached = true }
+
+    pub(crate) fn is_detached(&self) -> bool { self.detached }
+
+    pub(crate) fn position(&self) -> (usize, usize) { (self.index, self.generation) }
+
+    pub(crate) fn reclaim(&self) -> Result<(), Error> { self.buf.resume(RECEIVER_ATTACHED, self.index, self.generation) }
+
+    pub(crate) fn set_owner(&self, id: Identity) { self.buf.set_owner(RECEIVER_ATTACHED, id) }
+
+    pub(crate) fn owner(&self) -> &U { &self._owner }
+
+    /// The (empty, full) futex words in the header.
+    pub(crate) fn futexes(&self) -> (&AtomicU32, &AtomicU32) {
+        (&self.buf.header().empty_futex, &self.buf.header().full_futex)
+    }
+}
+
+impl<T, U> Drop for Receiver<T, U> {
+    fn drop(&mut self) { if !self.detached { self.close() } }
 }
 
 #[cfg(test)]
@@ -163,30 +631,50 @@ mod tests {
 
     #[test]
     fn owner() {
-        let mut v = vec![20; 30];
+        let mut v = vec![20; super::header_size() + 22];
         let v2: &mut[u8] = &mut *v;
         let (_, _) = super::channel::<i64, _>(v2);
     }
 
+    #[test]
+    fn attach() {
+        let mut v = vec![0u8; super::channel_bufsize::<u32>(4)];
+        assert_eq!(super::attach_sender::<u32, _>(&mut v[..]).err(), Some(super::Error::Invalid));
+        super::init::<u32>(&mut v);
+        assert_eq!(super::attach_receiver::<u16, _>(&mut v[..]).err(), Some(super::Error::Invalid));
+        {
+            let v2: &mut [u8] = &mut v;
+            let p = v2 as *mut [u8];
+            let mut s = super::attach_sender::<u32, _>(v2).unwrap();
+            let mut r = super::attach_receiver::<u32, _>(unsafe { &mut *p }).unwrap();
+            assert_eq!(super::attach_receiver::<u32, _>(unsafe { &mut *p }).err(), Some(super::Error::InUse));
+            s.send_foreach(5, |i| i as u32).unwrap();
+            r.recv(|d| { assert_eq!(d, &[0, 1, 2, 3]); 4 }).unwrap();
+        }
+        // A buffer too small for its header's capacity
+        let l = v.len() - 1;
+        assert_eq!(super::attach_sender::<u32, _>(&mut v[..l]).err(), Some(super::Error::Invalid));
+    }
+
     #[test]
     fn simple_test() {
-        let (mut s, mut r) = super::channel(vec![10; 20]);
+        let (mut s, mut r) = super::channel(vec![10; super::header_size() + 12]);
         // is it empty?
-        r.recv(|_| panic!());
-        s.send(|d, _| { unsafe { *d = 5u16 }; 1 });
+        r.recv(|_| panic!()).unwrap();
+        s.send(|d, _| { unsafe { *d = 5u16 }; 1 }).unwrap();
         r.recv(|d| { assert_eq!(d.len(), 1);
-            assert_eq!(d[0], 5); 0 });
+            assert_eq!(d[0], 5); 0 }).unwrap();
         r.recv(|d| { assert_eq!(d.len(), 1);
-            assert_eq!(d[0], 5); 1 });
-        r.recv(|_| panic!());
+            assert_eq!(d[0], 5); 1 }).unwrap();
+        r.recv(|_| panic!()).unwrap();
 
         let mut i = 6;
-        s.send_foreach(2, |_| { i += 1; i } );
+        s.send_foreach(2, |_| { i += 1; i } ).unwrap();
         r.recv(|d| { assert_eq!(d.len(), 2);
             assert_eq!(d[0], 7);
             assert_eq!(d[1], 8);
             2
-        });
+        }).unwrap();
     }
 
     #[test]
@@ -197,29 +685,120 @@ mod tests {
             let d = unsafe { ::std::slice::from_raw_parts_mut(dd, l) };
             d[0] = 5u16; d[1] = 8; d[2] = 9;
             2
-        });
+        }).unwrap();
         let mut called = false;
         s.send_foreach(2, |i| {
             assert_eq!(called, false);
             assert_eq!(i, 0);
             called = true;
             10
-        });
-        s.send(|_, _| panic!());
-        r.recv(|d| { assert_eq!(d.len(), 3); 0 });
-        s.send(|_, _| panic!());
+        }).unwrap();
+        s.send(|_, _| panic!()).unwrap();
+        r.recv(|d| { assert_eq!(d.len(), 3); 0 }).unwrap();
+        s.send(|_, _| panic!()).unwrap();
         r.recv(|d| { assert_eq!(d.len(), 3);
             assert_eq!(d[0], 5);
             assert_eq!(d[1], 8);
-            assert_eq!(d[2], 10); 1 });
-        s.send(|d, l| { assert_eq!(l, 1); unsafe { *d = 1 }; 1 });
-        s.send(|_, _| panic!());
+            assert_eq!(d[2], 10); 1 }).unwrap();
+        s.send(|d, l| { assert_eq!(l, 1); unsafe { *d = 1 }; 1 }).unwrap();
+        s.send(|_, _| panic!()).unwrap();
         r.recv(|d| { assert_eq!(d.len(), 2);
             assert_eq!(d[0], 8);
-            assert_eq!(d[1], 10); 2 });
+            assert_eq!(d[1], 10); 2 }).unwrap();
         r.recv(|d| { assert_eq!(d.len(), 1);
             assert_eq!(d[0], 1); 1
-        });
+        }).unwrap();
+    }
+
+    #[test]
+    fn disconnect_test() {
+        let (mut s, mut r) = super::channel(vec![0; super::channel_bufsize::<u16>(4)]);
+        s.send_foreach(2, |i| i as u16).unwrap();
+        assert!(!r.is_disconnected());
+        drop(s);
+        assert!(r.is_disconnected());
+        r.recv(|d| { assert_eq!(d, &[0, 1]); 2 }).unwrap();
+        assert_eq!(r.recv(|_| panic!()), Err(super::Error::Disconnected));
+
+        let (mut s, mut r) = super::channel::<u16, _>(vec![0; super::channel_bufsize::<u16>(4)]);
+        r.close();
+        assert!(s.is_disconnected());
+        assert_eq!(s.send(|_, _| panic!()), Err(super::Error::Disconnected));
+    }
+
+    #[test]
+    fn reset_test() {
+        let mut v = vec![0u8; super::channel_bufsize::<u16>(4)];
+        let (p, l) = (v.as_mut_ptr(), v.len());
+        let (mut s, mut r) = super::channel::<u16, _>(unsafe { ::std::slice::from_raw_parts_mut(p, l) });
+        s.send_foreach(2, |i| i as u16).unwrap();
+
+        let (mut s2, mut r2) = super::channel::<u16, _>(unsafe { ::std::slice::from_raw_parts_mut(p, l) });
+        assert_eq!(r.recv(|_| panic!()), Err(super::Error::Reset));
+        assert_eq!(s.send(|_, _| panic!()), Err(super::Error::Reset));
+        // Dropping the old sender must not close the new buffer
+        drop(s);
+        drop(r);
+        s2.send_foreach(1, |_| 7).unwrap();
+        r2.recv(|d| { assert_eq!(d, &[7]); 1 }).unwrap();
+    }
+
+    #[test]
+    fn sleep_test() {
+        let (mut s, r) = super::channel::<u16, _>(vec![0; super::channel_bufsize::<u16>(2)]);
+        assert!(!s.wake_needed());
+        assert_eq!(r.prepare_sleep(), Ok(0));
+        s.send_foreach(1, |_| 5).unwrap();
+        assert!(s.wake_needed());
+        assert!(!s.wake_needed());
+        // The sender already took the flag, so the receiver must wait for the wakeup
+        assert!(!r.cancel_sleep());
+
+        assert_eq!(r.prepare_sleep(), Ok(1));
+        assert!(r.cancel_sleep());
+        assert!(!s.wake_needed());
+
+        s.send_foreach(1, |_| 6).unwrap();
+        assert_eq!(s.prepare_sleep(), Ok(0));
+        drop(r);
+        assert_eq!(s.prepare_sleep(), Err(super::Error::Disconnected));
+    }
+
+    #[test]
+    fn poll_counts() {
+        use std::task::{Context, Poll, Wake, Waker};
+        use std::sync::Arc;
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        struct Count(AtomicUsize);
+        impl Wake for Count {
+            fn wake(self: Arc<Self>) { self.0.fetch_add(1, Ordering::SeqCst); }
+        }
+        let count = Arc::new(Count(AtomicUsize::new(0)));
+        let waker = Waker::from(count.clone());
+        let mut cx = Context::from_waker(&waker);
+
+        let (mut s, mut r) = super::channel::<u16, _>(vec![0; super::channel_bufsize::<u16>(2)]);
+        assert_eq!(r.poll_read_count(&mut cx), Poll::Pending);
+        s.send_foreach(2, |i| i as u16).unwrap();
+        assert_eq!(count.0.load(Ordering::SeqCst), 1);
+        assert_eq!(r.poll_read_count(&mut cx), Poll::Ready(Ok(2)));
+
+        assert_eq!(s.poll_write_count(&mut cx), Poll::Pending);
+        r.recv(|_| 1).unwrap();
+        assert_eq!(count.0.load(Ordering::SeqCst), 2);
+        assert_eq!(s.poll_write_count(&mut cx), Poll::Ready(Ok(1)));
+
+        r.recv(|_| 1).unwrap();
+        assert_eq!(r.poll_read_count(&mut cx), Poll::Pending);
+        drop(s);
+        assert_eq!(count.0.load(Ordering::SeqCst), 3);
+        assert_eq!(r.poll_read_count(&mut cx), Poll::Ready(Err(super::Error::Disconnected)));
+
+        // Only in-process channels can wake up tasks.
+        let mut v = vec![0u8; super::channel_bufsize::<u16>(2)];
+        super::init::<u16>(&mut v);
+        let mut r = super::attach_receiver::<u16, _>(v).unwrap();
+        assert_eq!(r.poll_read_count(&mut cx), Poll::Ready(Err(super::Error::Invalid)));
     }
 
     #[bench]
@@ -233,15 +812,15 @@ mod tests {
                 let mut c = 0;
                 for z in d.iter_mut().take(400) { *z = c; total1 += c as u64; c += 1; };
                 c as usize
-            });
+            }).unwrap();
             r.recv(|d| {
                 for z in d.iter().take(300) { total2 += *z as u64 };
                 ::std::cmp::min(300, d.len())
-            });
+            }).unwrap();
         });
 
-        r.recv(|d| { for z in d.iter() { total2 += *z as u64 }; d.len() });
-        r.recv(|d| { for z in d.iter() { total2 += *z as u64 }; d.len() });
+        r.recv(|d| { for z in d.iter() { total2 += *z as u64 }; d.len() }).unwrap();
+        r.recv(|d| { for z in d.iter() { total2 += *z as u64 }; d.len() }).unwrap();
 
         assert_eq!(total1, total2);
     }
@@ -253,15 +832,15 @@ mod tests {
         let (mut total1, mut total2) = (0u64, 0u64);
         b.iter(|| {
             let mut c = 0;
-            s.send_foreach(400, |_| { c += 1; total1 += c as u64; c });
+            s.send_foreach(400, |_| { c += 1; total1 += c as u64; c }).unwrap();
             r.recv(|d| {
                 for z in d.iter().take(300) { total2 += *z as u64 };
                 ::std::cmp::min(300, d.len())
-            });
+            }).unwrap();
         });
 
-        r.recv(|d| { for z in d.iter() { total2 += *z as u64 }; d.len() });
-        r.recv(|d| { for z in d.iter() { total2 += *z as u64 }; d.len() });
+        r.recv(|d| { for z in d.iter() { total2 += *z as u64 }; d.len() }).unwrap();
+        r.recv(|d| { for z in d.iter() { total2 += *z as u64 }; d.len() }).unwrap();
 
         assert_eq!(total1, total2);
     }
@@ -0,0 +1,261 @@
+//! Gives each client connecting to a Unix socket its own pair of shared ringbuffers.
+
+use std::io;
+use std::marker::PhantomData;
+use std::os::linux::net::SocketAddrExt;
+use std::os::unix::io::{RawFd, AsRawFd};
+use std::os::unix::net::{SocketAddr, UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use fdbuf::{Sender, Receiver};
+use mmap::Mmap;
+use peer::Identity;
+use shm;
+
+/// The process on the other side of a Unix socket, as reported by SO_PEERCRED.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Credentials {
+    pub pid: ::libc::pid_t,
+    pub uid: ::libc::uid_t,
+    pub gid: ::libc::gid_t,
+}
+
+impl Credentials {
+    fn of(socket: &UnixStream) -> io::Result<Credentials> {
+        let c = try!(shm::peer_credentials(socket));
+        Ok(Credentials { pid: c.pid, uid: c.uid, gid: c.gid })
+    }
+}
+
+/// A connection between a server and a client: a buffer in each direction, plus the socket
+/// they were set up over. Drop it when done (or when the other side has disconnected)
+/// to unmap the buffers and close the fds.
+///
+/// Nothing is released automatically when the client goes away, since the Server hands the
+/// connection over to you. Keep the connections in a Vec and call `remove_disconnected` on it
+/// whenever a `socket_fd` becomes readable (or a send/recv returns BrokenPipe or
+/// ConnectionAborted), or just now and then.
+pub struct Connection<S, R> {
+    /// Sends items to the other side.
+    pub sender: Sender<S, Mmap>,
+    /// Receives items from the other side.
+    pub receiver: Receiver<R, Mmap>,
+    socket: UnixStream,
+    credentials: Credentials,
+}
+
+impl<S: Send + Copy, R: Send + Copy> Connection<S, R> {
+    /// Connects to a Server listening on a path. S is what the client sends,
+    /// i e, what the server receives.
+    pub fn connect<P: AsRef<Path>>(path: P) -> io::Result<Connection<S, R>> {
+        Connection::setup_client(try!(UnixStream::connect(path)))
+    }
+
+    /// Connects to a Server listening on an abstract socket name.
+    pub fn connect_abstract(name: &[u8]) -> io::Result<Connection<S, R>> {
+        let addr = try!(SocketAddr::from_abstract_name(name));
+        Connection::setup_client(try!(UnixStream::connect_addr(&addr)))
+    }
+
+    fn setup_client(socket: UnixStream) -> io::Result<Connection<S, R>> {
+        let credentials = try!(Credentials::of(&socket));
+        // If the server rejects us, it just closes the socket.
+        let receiver = try!(shm::connect_receiver(&socket).map_err(|e|
+            if e.kind() == io::ErrorKind::UnexpectedEof { io::Error::new(io::ErrorKind::ConnectionRefused, "Rejected by the server") } else { e }));
+        let sender = try!(shm::connect_sender(&socket));
+        Ok(Connection { sender: sender, receiver: receiver, socket: socket, credentials: credentials })
+    }
+}
+
+impl<S, R> Connection<S, R> {
+    /// The process on the other side.
+    pub fn credentials(&self) -> Credentials { self.credentials }
+
+    /// The socket becomes readable when the other side disconnects.
+    pub fn socket_fd(&self) -> RawFd { self.socket.as_raw_fd() }
+
+    /// Drops the connections whose other side has closed the socket (or died), and returns how many.
+    /// Connections that can't be checked are dropped too.
+    pub fn remove_disconnected(connections: &mut Vec<Connection<S, R>>) -> usize {
+        let n = connections.len();
+        connections.retain(|c| match c.is_disconnected() {
+            Ok(d) => !d,
+            Err(e) => { debug!("Dropping connection to {:?}: {}", c.credentials, e); false },
+        });
+        n - connections.len()
+    }
+
+    /// Returns true if the other side has closed the socket.
+    pub fn is_disconnected(&self) -> io::Result<bool> {
+        let mut b = [0u8; 1];
+        let e = unsafe { ::libc::recv(self.socket.as_raw_fd(), b.as_mut_ptr() as *mut ::libc::c_void, 1,
+            ::libc::MSG_PEEK | ::libc::MSG_DONTWAIT) };
+        if e >= 0 { return Ok(e == 0) };
+        let err = io::Error::last_os_error();
+        match err.kind() {
+            io::ErrorKind::WouldBlock => Ok(false),
+            io::ErrorKind::ConnectionReset => Ok(true),
+            _ => Err(err),
+        }
+    }
+}
+
+/// Listens on a Unix socket, and sets up a sealed shared buffer in each direction for every
+/// client that passes the policy. S is what the server sends, R is what it receives.
+///
+/// The Server does not keep track of the connections it hands out: cleaning up after
+/// clients that disconnect is up to you, see `Connection::remove_disconnected`.
+/// A client that dies is noticed by the blocking and timeout versions of send and recv,
+/// which return ConnectionAborted, even if it died before attaching its side.
+pub struct Server<S, R> {
+    listener: UnixListener,
+    /// Removed on drop, for filesystem sockets.
+    path: Option<PathBuf>,
+    capacity: usize,
+    policy: Box<dyn Fn(&Credentials) -> bool + Send>,
+    _t: PhantomData<(S, R)>,
+}
+
+impl<S: Send + Copy, R: Send + Copy> Server<S, R> {
+    fn new(listener: UnixListener, path: Option<PathBuf>) -> Server<S, R> {
+        let uid = unsafe { ::libc::geteuid() };
+        Server { listener: listener, path: path, capacity: 1024, policy: Box::new(move |c| c.uid == uid), _t: PhantomData }
+    }
+
+    /// Listens on a filesystem path. The socket file is removed when the Server is dropped.
+    pub fn bind<P: AsRef<Path>>(path: P) -> io::Result<Server<S, R>> {
+        let l = try!(UnixListener::bind(path.as_ref()));
+        Ok(Server::new(l, Some(path.as_ref().into())))
+    }
+
+    /// Listens on an abstract socket name (which does not show up in the filesystem).
+    pub fn bind_abstract(name: &[u8]) -> io::Result<Server<S, R>> {
+        let addr = try!(SocketAddr::from_abstract_name(name));
+        Ok(Server::new(try!(UnixListener::bind_addr(&addr)), None))
+    }
+
+    /// Decides which clients to accept. The default is to accept clients running as the same user.
+    pub fn set_policy<F: Fn(&Credentials) -> bool + Send + 'static>(&mut self, f: F) { self.policy = Box::new(f) }
+
+    /// The number of items in each buffer. The default is 1024.
+    pub fn set_capacity(&mut self, capacity: usize) { self.capacity = capacity }
+
+    /// Waits for the next client, and sets up its buffers.
+    /// Returns an error of kind PermissionDenied if the client was rejected by the policy
+    /// (the client gets ConnectionRefused); just call accept again.
+    ///
+    /// The connection stays around until you drop it, see `Connection::remove_disconnected`.
+    pub fn accept(&self) -> io::Result<Connection<S, R>> {
+        let (socket, _) = try!(self.listener.accept());
+        let credentials = try!(Credentials::of(&socket));
+        if !(self.policy)(&credentials) {
+            debug!("Rejected client {:?}", credentials);
+            return Err(io::Error::new(io::ErrorKind::PermissionDenied, "Client rejected by policy"));
+        }
+        // So that we notice if the client dies before attaching.
+        let peer = Identity::of(credentials.pid as usize);
+        let sender = try!(shm::Builder::new(self.capacity).name("fdringbuf-server").send_receiver_to(&socket, peer));
+        let receiver = try!(shm::Builder::new(self.capacity).name("fdringbuf-client").send_sender_to(&socket, peer));
+        Ok(Connection { sender: sender, receiver: receiver, socket: socket, credentials: credentials })
+    }
+}
+
+impl<S, R> AsRawFd for Server<S, R> {
+    /// Readable when a client is waiting to be accepted.
+    fn as_raw_fd(&self) -> RawFd { self.listener.as_raw_fd() }
+}
+
+impl<S, R> Drop for Server<S, R> {
+    fn drop(&mut self) {
+        if let Some(ref p) = self.path { let _ = ::std::fs::remove_file(p); }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Server, Connection};
+
+    fn name(n: &str) -> Vec<u8> { format!("fdringbuf-test-{}-{}", n, unsafe { ::libc::getpid() }).into_bytes() }
+
+    #[test]
+    fn server_roundtrip() {
+        let name = name("roundtrip");
+        let mut server = Server::<u32, u8>::bind_abstract(&name).unwrap();
+        server.set_capacity(4);
+        let client = ::std::thread::spawn(move || {
+            let mut c = Connection::<u8, u32>::connect_abstract(&name).unwrap();
+            assert_eq!(c.credentials().pid, unsafe { ::libc::getpid() });
+            c.sender.send_foreach(1, |_| 9).unwrap();
+            let mut v = 0;
+            c.receiver.recv_blocking(|d| { v = d[0]; (1, false) }).unwrap();
+            v
+        });
+        let mut c = server.accept().unwrap();
+        c.receiver.recv_blocking(|d| { assert_eq!(d, &[9]); (1, false) }).unwrap();
+        c.sender.send_foreach(1, |_| 1234).unwrap();
+        assert_eq!(client.join().unwrap(), 1234);
+        assert!(c.is_disconnected().unwrap());
+    }
+
+    #[test]
+    fn cleanup() {
+        let name = name("cleanup");
+        let server = Server::<u32, u32>::bind_abstract(&name).unwrap();
+        let (tx, rx) = ::std::sync::mpsc::channel::<()>();
+        let n2 = name.clone();
+        let clients = ::std::thread::spawn(move || {
+            let _stays = Connection::<u32, u32>::connect_abstract(&n2).unwrap();
+            drop(Connection::<u32, u32>::connect_abstract(&n2).unwrap());
+            rx.recv().unwrap();
+        });
+        let mut connections = vec![server.accept().unwrap(), server.accept().unwrap()];
+        while !connections[1].is_disconnected().unwrap() { ::std::thread::yield_now() }
+        assert_eq!(Connection::remove_disconnected(&mut connections), 1);
+        assert_eq!(connections.len(), 1);
+        tx.send(()).unwrap();
+        clients.join().unwrap();
+        assert_eq!(Connection::remove_disconnected(&mut connections), 1);
+        assert!(connections.is_empty());
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn dead_client() {
+        use std::io::Write;
+        let name = name("dead-client");
+        let server = Server::<u32, u32>::bind_abstract(&name).unwrap();
+        let (child, mut socket) = ::child::spawn("server::tests::dead_client_child");
+        let mut c = server.accept().unwrap();
+        socket.write_all(&[1]).unwrap();
+        let e = c.receiver.recv_timeout(::std::time::Duration::from_secs(5), |_| (0, false)).unwrap_err();
+        assert_eq!(e.kind(), ::std::io::ErrorKind::ConnectionAborted);
+        ::child::wait(child);
+    }
+
+    // Connects, but dies without attaching to the buffers.
+    #[cfg(target_os = "linux")]
+    #[test]
+    #[ignore]
+    fn dead_client_child() {
+        use std::io::Read;
+        use std::os::linux::net::SocketAddrExt;
+        let mut socket = match ::child::socket() { Some(s) => s, None => return };
+        let name = format!("fdringbuf-test-dead-client-{}", unsafe { ::libc::getppid() }).into_bytes();
+        let addr = ::std::os::unix::net::SocketAddr::from_abstract_name(&name).unwrap();
+        let _c = ::std::os::unix::net::UnixStream::connect_addr(&addr).unwrap();
+        socket.read_exact(&mut [0]).unwrap();
+        unsafe { ::libc::_exit(0) };
+    }
+
+    #[test]
+    fn rejected() {
+        let dir = ::std::env::temp_dir().join(format!("fdringbuf-test-{}", unsafe { ::libc::getpid() }));
+        let mut server = Server::<u32, u32>::bind(&dir).unwrap();
+        server.set_policy(|_| false);
+        let d2 = dir.clone();
+        let client = ::std::thread::spawn(move || Connection::<u32, u32>::connect(&d2).err().unwrap().kind());
+        assert_eq!(server.accept().err().unwrap().kind(), ::std::io::ErrorKind::PermissionDenied);
+        assert_eq!(client.join().unwrap(), ::std::io::ErrorKind::ConnectionRefused);
+        drop(server);
+        assert!(!dir.exists());
+    }
+}
@@ -0,0 +1,828 @@
+//! Sets up an fdbuf in shared memory between two processes, connected through a Unix socket.
+//!
+//! One side creates the buffer (a memfd) and two eventfds with a `Builder`, keeps one endpoint,
+//! and sends the fds to the other side, which gets the other endpoint by calling
+//! `connect_sender` or `connect_receiver`.
+//!
+//! The memfd is sealed, so that neither side can shrink it (which would make the other side
+//! crash with SIGBUS), and the connecting side checks the seals and the buffer size before using it.
+//!
+//! Processes that don't share a socket can instead create and open buffers by name, see
+//! `Builder::create_sender` and `open_receiver`. The buffer is then a POSIX shared memory object,
+//! and the signalling is done through FIFOs next to it, in /dev/shm.
+//!
+//! To share a buffer with a child process, create it with `Builder::fork` before forking.
+//!
+//! An endpoint set up through a socket (or a Server) can later be moved to yet another process,
+//! without draining it first, with `handoff_sender` and `takeover_sender` (or the receiver versions).
+
+use std::io;
+use std::os::unix::io::{RawFd, OwnedFd, AsRawFd, FromRawFd};
+use std::os::unix::net::UnixStream;
+use std::os::unix::ffi::OsStrExt;
+use std::ops::{Deref, DerefMut};
+use std::ffi::CString;
+use std::path::{Path, PathBuf};
+use std::marker::PhantomData;
+use std::{error, fmt};
+use fdbuf::{self, OwnedPipe, Pipe, cvt};
+use mmap::Mmap;
+use peer::Identity;
+
+/// Sent together with the fds, so we know we're talking to the right thing.
+const HELLO: &[u8; 8] = b"fdrbshm1";
+const SENDER: u8 = 1;
+const RECEIVER: u8 = 2;
+/// Sent together with the fds of a live endpoint, followed by the role, index, generation,
+/// sleeping state and which of the two pipes have separate reader and writer fds.
+const HANDOFF: &[u8; 8] = b"fdrbhof1";
+const HANDOFF_LEN: usize = 8 + 1 + 8 + 8 + 1 + 1;
+const SIGNAL_SPLIT: u8 = 1;
+const WAIT_SPLIT: u8 = 2;
+/// The taking over side answers with the role (or zero if it refuses) and its pid and start time.
+const READY_LEN: usize = 1 + 8 + 8;
+
+/// Sends data, and fds as SCM_RIGHTS.
+pub(crate) fn send_fds(socket: &UnixStream, data: &[u8], fds: &[RawFd]) -> io::Result<()> {
+    let fdlen = fds.len() * ::std::mem::size_of::<RawFd>();
+    let mut cbuf = vec![0u8; unsafe { ::libc::CMSG_SPACE(fdlen as u32) } as usize];
+    let mut iov = ::libc::iovec { iov_base: data.as_ptr() as *mut ::libc::c_void, iov_len: data.len() };
+    let mut msg: ::libc::msghdr = unsafe { ::std::mem::zeroed() };
+    msg.msg_iov = &mut iov;
+    msg.msg_iovlen = 1;
+    if !fds.is_empty() {
+        msg.msg_control = cbuf.as_mut_ptr() as *mut ::libc::c_void;
+        msg.msg_controllen = cbuf.len() as _;
+        unsafe {
+            let c = ::libc::CMSG_FIRSTHDR(&msg);
+            (*c).cmsg_level = ::libc::SOL_SOCKET;
+            (*c).cmsg_type = ::libc::SCM_RIGHTS;
+            (*c).cmsg_len = ::libc::CMSG_LEN(fdlen as u32) as _;
+            ::std::ptr::copy_nonoverlapping(fds.as_ptr(), ::libc::CMSG_DATA(c) as *mut RawFd, fds.len());
+        }
+    }
+    loop {
+        let e = unsafe { ::libc::sendmsg(socket.as_raw_fd(), &msg, ::libc::MSG_NOSIGNAL) };
+        if e >= 0 {
+            if e as usize != data.len() { return Err(io::Error::new(io::ErrorKind::WriteZero, "Short write to socket")) };
+            return Ok(());
+        }
+        let err = io::Error::last_os_error();
+        if err.kind() != io::ErrorKind::Interrupted { return Err(err) };
+    }
+}
+
+/// The process on the other side of a Unix socket (as of when it connected), from SO_PEERCRED.
+pub(crate) fn peer_credentials(socket: &UnixStream) -> io::Result<::libc::ucred> {
+    let mut c: ::libc::ucred = unsafe { ::std::mem::zeroed() };
+    let mut len = ::std::mem::size_of::<::libc::ucred>() as ::libc::socklen_t;
+    try!(cvt(unsafe { ::libc::getsockopt(socket.as_raw_fd(), ::libc::SOL_SOCKET, ::libc::SO_PEERCRED,
+        &mut c as *mut _ as *mut ::libc::c_void, &mut len) }));
+    Ok(c)
+}
+
+/// Receives exactly data.len() bytes, and up to maxfds fds (in the same message).
+pub(crate) fn recv_fds(socket: &UnixStream, data: &mut [u8], maxfds: usize) -> io::Result<Vec<OwnedFd>> {
+    let fdlen = maxfds * ::std::mem::size_of::<RawFd>();
+    let mut cbuf = vec![0u8; unsafe { ::libc::CMSG_SPACE(fdlen as u32) } as usize];
+    let mut iov = ::libc::iovec { iov_base: data.as_mut_ptr() as *mut ::libc::c_void, iov_len: data.len() };
+    let mut msg: ::libc::msghdr = unsafe { ::std::mem::zeroed() };
+    msg.msg_iov = &mut iov;
+    msg.msg_iovlen = 1;
+    msg.msg_control = cbuf.as_mut_ptr() as *mut ::libc::c_void;
+    msg.msg_controllen = cbuf.len() as _;
+    let n = loop {
+        let e = unsafe { ::libc::recvmsg(socket.as_raw_fd(), &mut msg, ::libc::MSG_CMSG_CLOEXEC) };
+        if e >= 0 { break e as usize };
+        let err = io::Error::last_os_error();
+        if err.kind() != io::ErrorKind::Interrupted { return Err(err) };
+    };
+    // Take ownership of the fds first, so they're closed if something is wrong.
+    let mut fds = vec![];
+    unsafe {
+        let mut c = ::libc::CMSG_FIRSTHDR(&msg);
+        while !c.is_null() {
+            if (*c).cmsg_level == ::libc::SOL_SOCKET && (*c).cmsg_type == ::libc::SCM_RIGHTS {
+                let count = ((*c).cmsg_len as usize - ::libc::CMSG_LEN(0) as usize) / ::std::mem::size_of::<RawFd>();
+                let p = ::libc::CMSG_DATA(c) as *const RawFd;
+                for i in 0..count { fds.push(OwnedFd::from_raw_fd(::std::ptr::read_unaligned(p.add(i)))) }
+            }
+            c = ::libc::CMSG_NXTHDR(&msg, c);
+        }
+    }
+    if n == 0 { return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "Socket closed")) };
+    if n != data.len() || msg.msg_flags & (::libc::MSG_TRUNC | ::libc::MSG_CTRUNC) != 0 {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "Unexpected message on socket"));
+    }
+    Ok(fds)
+}
+
+/// Creates a shared ringbuffer, and sends one of its endpoints to another process.
+pub struct Builder<T> {
+    capacity: usize,
+    name: String,
+    mode: ::libc::mode_t,
+    huge: bool,
+    _t: PhantomData<T>,
+}
+
+impl<T: Send + Copy> Builder<T> {
+    /// capacity is the number of items the buffer can hold.
+    pub fn new(capacity: usize) -> Builder<T> {
+        Builder { capacity: capacity, name: "fdringbuf".into(), mode: 0o600, huge: false, _t: PhantomData }
+    }
+
+    /// Permission bits for named buffers (and their FIFOs). The default is 0o600.
+    pub fn mode(mut self, mode: u32) -> Builder<T> { self.mode = mode as ::libc::mode_t; self }
+
+    /// The name of the memfd, which shows up in /proc/<pid>/maps (for debugging).
+    pub fn name(mut self, name: &str) -> Builder<T> { self.name = name.into(); self }
+
+    /// Use huge pages for the buffer, if available (see `Mmap::huge_anonymous`). The capacity
+    /// is then rounded up to fill a whole number of huge pages. Not used for named buffers.
+    pub fn huge_pages(mut self, huge: bool) -> Builder<T> { self.huge = huge; self }
+
+    /// Creates the buffer and eventfds.
+    fn create(&self) -> io::Result<(Mmap, OwnedPipe, OwnedPipe)> {
+        let len = ::ringbuf::channel_bufsize::<T>(self.capacity);
+        let mut mem = try!(if self.huge { Mmap::sealed_huge_memfd(&self.name, len) } else { Mmap::sealed_memfd(&self.name, len) });
+        ::ringbuf::init::<T>(&mut mem);
+        let empty = unsafe { OwnedPipe::from_pipe(try!(Pipe::eventfd())) };
+        let full = unsafe { OwnedPipe::from_pipe(try!(Pipe::eventfd())) };
+        Ok((mem, empty, full))
+    }
+
+    /// The memfd and the readers of the eventfds, to send.
+    fn fds(mem: &Mmap, empty: &OwnedPipe, full: &OwnedPipe) -> [RawFd; 3] {
+        [mem.fd().unwrap().as_raw_fd(), empty.as_pipe().reader, full.as_pipe().reader]
+    }
+
+    fn send(socket: &UnixStream, side: u8, fds: &[RawFd; 3]) -> io::Result<()> {
+        let mut data = [0u8; 9];
+        data[..8].copy_from_slice(HELLO);
+        data[8] = side;
+        send_fds(socket, &data, fds)
+    }
+
+    /// Sends the receiving side to the other process (which calls connect_receiver),
+    /// and returns the sending side.
+    ///
+    /// The process that connected the socket is recorded as the receiver, so that blocking
+    /// sends fail with ConnectionAborted if it dies, even before it has attached.
+    pub fn send_receiver(self, socket: &UnixStream) -> io::Result<fdbuf::Sender<T, Mmap>> {
+        let peer = Identity::of(try!(peer_credentials(socket)).pid as usize);
+        self.send_receiver_to(socket, peer)
+    }
+
+    pub(crate) fn send_receiver_to(self, socket: &UnixStream, peer: Identity) -> io::Result<fdbuf::Sender<T, Mmap>> {
+        let (mem, empty, full) = try!(self.create());
+        let fds = Builder::<T>::fds(&mem, &empty, &full);
+        let s = try!(fdbuf::attach_sender(mem, empty, full));
+        // Before sending, so that the other side can't attach (and be overwritten) first.
+        s.set_peer(peer);
+        try!(Builder::<T>::send(socket, RECEIVER, &fds));
+        Ok(s)
+    }
+
+    /// Sends the sending side to the other process (which calls connect_sender),
+    /// and returns the receiving side. See send_receiver.
+    pub fn send_sender(self, socket: &UnixStream) -> io::Result<fdbuf::Receiver<T, Mmap>> {
+        let peer = Identity::of(try!(peer_credentials(socket)).pid as usize);
+        self.send_sender_to(socket, peer)
+    }
+
+    pub(crate) fn send_sender_to(self, socket: &UnixStream, peer: Identity) -> io::Result<fdbuf::Receiver<T, Mmap>> {
+        let (mem, empty, full) = try!(self.create());
+        let fds = Builder::<T>::fds(&mem, &empty, &full);
+        let r = try!(fdbuf::attach_receiver(mem, empty, full));
+        r.set_peer(peer);
+        try!(Builder::<T>::send(socket, SENDER, &fds));
+        Ok(r)
+    }
+}
+
+impl<T: Send + Copy> Builder<T> {
+    fn create_named(&self, name: &str) -> io::Result<(Named, OwnedPipe, OwnedPipe)> {
+        let paths = try!(Paths::new(name));
+        let mut named = Named { map: None, unlink: None };
+        // Create the FIFOs first, so they exist once the buffer is initialized.
+        let empty = try!(Paths::create_fifo(&paths.empty, self.mode));
+        named.unlink = Some(Paths { shm: None, .. paths.clone() });
+        let full = try!(Paths::create_fifo(&paths.full, self.mode));
+
+        let fd = try!(cvt(unsafe { ::libc::shm_open(paths.shm.as_ref().unwrap().as_ptr(),
+            ::libc::O_RDWR | ::libc::O_CREAT | ::libc::O_EXCL | ::libc::O_CLOEXEC, self.mode) }));
+        let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+        named.unlink = Some(paths);
+        // Not affected by umask, unlike shm_open.
+        try!(cvt(unsafe { ::libc::fchmod(fd.as_raw_fd(), self.mode) }));
+        let len = ::ringbuf::channel_bufsize::<T>(self.capacity);
+        try!(cvt(unsafe { ::libc::ftruncate(fd.as_raw_fd(), len as ::libc::off_t) }));
+        let mut map = try!(Mmap::from_fd(fd, len));
+        ::ringbuf::init::<T>(&mut map);
+        named.map = Some(map);
+        Ok((named, empty, full))
+    }
+
+    /// Creates a buffer that other processes can open by name (with open_receiver), and returns
+    /// the sending side. The name must not contain slashes. The buffer and FIFOs are removed
+    /// when the sender is dropped.
+    pub fn create_sender(self, name: &str) -> io::Result<fdbuf::Sender<T, Named>> {
+        let (named, empty, full) = try!(self.create_named(name));
+        fdbuf::attach_sender(named, empty, full)
+    }
+
+    /// Like create_sender, but returns the receiving side; the other process calls open_sender.
+    pub fn create_receiver(self, name: &str) -> io::Result<fdbuf::Receiver<T, Named>> {
+        let (named, empty, full) = try!(self.create_named(name));
+        fdbuf::attach_receiver(named, empty, full)
+    }
+}
+
+impl<T: Send + Copy> Builder<T> {
+    /// Creates a buffer in anonymous shared memory, to be split between a parent and a child
+    /// process after fork. The name is not used.
+    pub fn fork(self) -> io::Result<Forked<T>> {
+        let len = ::ringbuf::channel_bufsize::<T>(self.capacity);
+        let mut mem = try!(if self.huge { Mmap::huge_anonymous(len) } else { Mmap::anonymous(len) });
+        ::ringbuf::init::<T>(&mut mem);
+        let empty = unsafe { OwnedPipe::from_pipe(try!(Pipe::eventfd())) };
+        let full = unsafe { OwnedPipe::from_pipe(try!(Pipe::eventfd())) };
+        Ok(Forked { mem: mem, empty: empty, full: full, _t: PhantomData })
+    }
+}
+
+/// A buffer created by Builder::fork, with no endpoint attached yet.
+///
+/// Call fork, then in the parent call parent_sender (or parent_receiver) with the child's pid,
+/// and in the child into_receiver (or into_sender). The parent records the child as its peer
+/// right away, so it notices if the child dies before attaching. The handle is consumed, so each
+/// process only keeps the fds and mapping of its own endpoint; they are closed (and unmapped)
+/// when the endpoint is dropped.
+///
+/// If the parent has other threads, the child may only make async-signal-safe calls until it
+/// execs, which attaching is not (it allocates). Fork before starting other threads then.
+pub struct Forked<T> {
+    mem: Mmap,
+    empty: OwnedPipe,
+    full: OwnedPipe,
+    _t: PhantomData<T>,
+}
+
+impl<T: Send + Copy> Forked<T> {
+    pub fn into_sender(self) -> io::Result<fdbuf::Sender<T, Mmap>> {
+        fdbuf::attach_sender(self.mem, self.empty, self.full)
+    }
+
+    pub fn into_receiver(self) -> io::Result<fdbuf::Receiver<T, Mmap>> {
+        fdbuf::attach_receiver(self.mem, self.empty, self.full)
+    }
+
+    /// In the parent, right after fork: attaches the sender, with the child as the receiver.
+    pub fn parent_sender(self, child: ::libc::pid_t) -> io::Result<fdbuf::Sender<T, Mmap>> {
+        let s = try!(self.into_sender());
+        s.set_peer(Identity::of(child as usize));
+        Ok(s)
+    }
+
+    /// In the parent, right after fork: attaches the receiver, with the child as the sender.
+    pub fn parent_receiver(self, child: ::libc::pid_t) -> io::Result<fdbuf::Receiver<T, Mmap>> {
+        let r = try!(self.into_receiver());
+        r.set_peer(Identity::of(child as usize));
+        Ok(r)
+    }
+}
+
+/// Where shm_open puts the shared memory objects on Linux.
+const FIFO_DIR: &str = "/dev/shm";
+
+/// Where the shared memory object and FIFOs for a named buffer are.
+#[derive(Clone)]
+struct Paths {
+    shm: Option<CString>,
+    empty: PathBuf,
+    full: PathBuf,
+}
+
+impl Paths {
+    fn new(name: &str) -> io::Result<Paths> {
+        if name.is_empty() || name.contains('/') {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "Invalid name for a shared buffer"));
+        }
+        let shm = try!(CString::new(format!("/{}", name)).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e)));
+        // Only depends on the name, so that processes with different environments (or users) agree.
+        let dir = Path::new(FIFO_DIR);
+        Ok(Paths {
+            shm: Some(shm),
+            empty: dir.join(format!("fdringbuf-{}.empty", name)),
+            full: dir.join(format!("fdringbuf-{}.full", name)),
+        })
+    }
+
+    fn create_fifo(path: &Path, mode: ::libc::mode_t) -> io::Result<OwnedPipe> {
+        let p = try!(CString::new(path.as_os_str().as_bytes()).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e)));
+        try!(cvt(unsafe { ::libc::mkfifo(p.as_ptr(), mode) }));
+        try!(cvt(unsafe { ::libc::chmod(p.as_ptr(), mode) }));
+        Paths::open_fifo(path)
+    }
+
+    /// Opening for both reading and writing does not block, and gives us an fd that works
+    /// like an eventfd.
+    fn open_fifo(path: &Path) -> io::Result<OwnedPipe> {
+        let p = try!(CString::new(path.as_os_str().as_bytes()).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e)));
+        let fd = try!(cvt(unsafe { ::libc::open(p.as_ptr(), ::libc::O_RDWR | ::libc::O_NONBLOCK | ::libc::O_CLOEXEC) }));
+        let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+        let mut st: ::libc::stat = unsafe { ::std::mem::zeroed() };
+        try!(cvt(unsafe { ::libc::fstat(fd.as_raw_fd(), &mut st) }));
+        if st.st_mode & ::libc::S_IFMT != ::libc::S_IFIFO {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "Not a FIFO"));
+        }
+        Ok(OwnedPipe::shared(fd))
+    }
+
+    fn unlink(&self) {
+        if let Some(ref shm) = self.shm { unsafe { ::libc::shm_unlink(shm.as_ptr()) }; }
+        let _ = ::std::fs::remove_file(&self.empty);
+        let _ = ::std::fs::remove_file(&self.full);
+    }
+}
+
+/// A named shared buffer, created by Builder::create_sender (or create_receiver),
+/// or opened by open_sender (or open_receiver). The creator removes the name when dropped.
+pub struct Named {
+    map: Option<Mmap>,
+    unlink: Option<Paths>,
+}
+
+impl Deref for Named {
+    type Target = [u8];
+    fn deref(&self) -> &[u8] { self.map.as_ref().unwrap() }
+}
+
+impl DerefMut for Named {
+    fn deref_mut(&mut self) -> &mut [u8] { self.map.as_mut().unwrap() }
+}
+
+impl Drop for Named {
+    fn drop(&mut self) {
+        if let Some(ref p) = self.unlink { p.unlink() };
+    }
+}
+
+fn open_named(name: &str) -> io::Result<(Named, OwnedPipe, OwnedPipe)> {
+    let paths = try!(Paths::new(name));
+    let fd = try!(cvt(unsafe { ::libc::shm_open(paths.shm.as_ref().unwrap().as_ptr(), ::libc::O_RDWR | ::libc::O_CLOEXEC, 0) }));
+    let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+    // The header is checked when attaching, so a buffer for another type is rejected.
+    let map = try!(Mmap::from_fd_all(fd));
+    let empty = try!(Paths::open_fifo(&paths.empty));
+    let full = try!(Paths::open_fifo(&paths.full));
+    Ok((Named { map: Some(map), unlink: None }, empty, full))
+}
+
+/// Opens the sending side of a buffer created by Builder::create_receiver.
+/// Returns an error of kind InvalidData if it was created for another type.
+pub fn open_sender<T: Send + Copy>(name: &str) -> io::Result<fdbuf::Sender<T, Named>> {
+    let (named, empty, full) = try!(open_named(name));
+    fdbuf::attach_sender(named, empty, full)
+}
+
+/// Opens the receiving side of a buffer created by Builder::create_sender.
+/// Returns an error of kind InvalidData if it was created for another type.
+pub fn open_receiver<T: Send + Copy>(name: &str) -> io::Result<fdbuf::Receiver<T, Named>> {
+    let (named, empty, full) = try!(open_named(name));
+    fdbuf::attach_receiver(named, empty, full)
+}
+
+/// Receives the fds, and maps the buffer.
+fn connect(socket: &UnixStream, side: u8) -> io::Result<(Mmap, OwnedPipe, OwnedPipe)> {
+    let mut data = [0u8; 9];
+    let mut fds = try!(recv_fds(socket, &mut data, 3));
+    if &data[..8] != HELLO || fds.len() != 3 {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "Not an fdringbuf setup message"));
+    }
+    if data[8] != side {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "The other side sent the wrong endpoint"));
+    }
+    let full = OwnedPipe::shared(fds.pop().unwrap());
+    let empty = OwnedPipe::shared(fds.pop().unwrap());
+    // The size is checked against the header when attaching.
+    let mem = try!(Mmap::from_sealed_fd(fds.pop().unwrap()));
+    Ok((mem, empty, full))
+}
+
+/// Receives the sending side of a buffer, sent by Builder::send_sender in another process.
+/// Fails if the buffer was created for another type.
+pub fn connect_sender<T: Send + Copy>(socket: &UnixStream) -> io::Result<fdbuf::Sender<T, Mmap>> {
+    let (mem, empty, full) = try!(connect(socket, SENDER));
+    fdbuf::attach_sender(mem, empty, full)
+}
+
+/// Receives the receiving side of a buffer, sent by Builder::send_receiver in another process.
+/// Fails if the buffer was created for another type.
+pub fn connect_receiver<T: Send + Copy>(socket: &UnixStream) -> io::Result<fdbuf::Receiver<T, Mmap>> {
+    let (mem, empty, full) = try!(connect(socket, RECEIVER));
+    fdbuf::attach_receiver(mem, empty, full)
+}
+
+/// A failed handoff. The endpoint is still ours, and can be used (or handed off) again.
+pub struct HandoffError<E> {
+    error: io::Error,
+    // Boxed, so that the Result stays small.
+    endpoint: Box<E>,
+}
+
+impl<E> HandoffError<E> {
+    pub fn error(&self) -> &io::Error { &self.error }
+
+    /// Gets the endpoint back.
+    pub fn into_inner(self) -> E { *self.endpoint }
+}
+
+impl<E> fmt::Debug for HandoffError<E> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result { write!(f, "HandoffError({:?})", self.error) }
+}
+
+impl<E> fmt::Display for HandoffError<E> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result { write!(f, "Handoff failed: {}", self.error) }
+}
+
+impl<E> error::Error for HandoffError<E> {}
+
+impl<E> From<HandoffError<E>> for io::Error {
+    fn from(e: HandoffError<E>) -> io::Error { e.error }
+}
+
+fn u64_at(data: &[u8], i: usize) -> usize {
+    let mut n = [0u8; 8];
+    n.copy_from_slice(&data[i..i + 8]);
+    u64::from_ne_bytes(n) as usize
+}
+
+/// The fds to send for a pipe, and whether they are two different fds.
+fn pipe_fds(p: Pipe) -> (Vec<RawFd>, bool) {
+    if p.reader == p.writer { (vec![p.reader], false) } else { (vec![p.reader, p.writer], true) }
+}
+
+/// Hands off an endpoint in three steps, so that exactly one process ends up owning it:
+/// we send the endpoint's state and fds, the other side checks them and answers with its
+/// identity, and we record it as the owner and tell it to go ahead. If anything fails before
+/// that, the endpoint is still ours.
+fn handoff<F: FnOnce(Identity)>(socket: &UnixStream, side: u8, h: fdbuf::Handoff<Mmap>, set_owner: F) -> io::Result<()> {
+    let memfd = try!(h.mem.fd().ok_or(io::Error::new(io::ErrorKind::InvalidInput, "The buffer has no fd to send"))).as_raw_fd();
+    let (signal, signal_split) = pipe_fds(h.signal);
+    let (wait, wait_split) = pipe_fds(h.wait);
+    let mut data = [0u8; HANDOFF_LEN];
+    data[..8].copy_from_slice(HANDOFF);
+    data[8] = side;
+    data[9..17].copy_from_slice(&(h.index as u64).to_ne_bytes());
+    data[17..25].copy_from_slice(&(h.generation as u64).to_ne_bytes());
+    data[25] = h.sleeping as u8;
+    data[26] = if signal_split { SIGNAL_SPLIT } else { 0 } | if wait_split { WAIT_SPLIT } else { 0 };
+    let fds: Vec<RawFd> = Some(memfd).into_iter().chain(signal).chain(wait).collect();
+    try!(send_fds(socket, &data, &fds));
+
+    let mut ready = [0u8; READY_LEN];
+    try!(recv_fds(socket, &mut ready, 0));
+    if ready[0] == 0 { return Err(io::Error::new(io::ErrorKind::ConnectionRefused, "The other side refused the handoff")) };
+    if ready[0] != side { return Err(io::Error::new(io::ErrorKind::InvalidData, "Unexpected handoff confirmation")) };
+    // From now on, the other side is watched (and woken up) instead of us.
+    set_owner(Identity { pid: u64_at(&ready, 1), start: u64_at(&ready, 9) });
+    send_fds(socket, &[side], &[])
+}
+
+/// Moves a sender to another process, which calls takeover_sender. Items in the buffer stay
+/// there, and the receiver is not disturbed. Blocks until the other process has taken over.
+///
+/// The buffer must be a memfd, e g one set up by Builder, connect_sender or a Server.
+/// On failure, the sender is returned in the error, still usable, and the other process
+/// does not get it.
+pub fn handoff_sender<T>(sender: fdbuf::Sender<T, Mmap>, socket: &UnixStream) -> Result<(), HandoffError<fdbuf::Sender<T, Mmap>>> {
+    match handoff(socket, SENDER, sender.handoff(), |id| sender.set_owner(id)) {
+        Ok(()) => { sender.detach(); Ok(()) },
+        Err(e) => {
+            // We might have recorded the other side as the owner before failing.
+            if let Err(e2) = sender.reclaim() { debug!("Failed to reclaim the sender: {}", e2) };
+            Err(HandoffError { error: e, endpoint: Box::new(sender) })
+        }
+    }
+}
+
+/// Moves a receiver to another process, which calls takeover_receiver. See handoff_sender.
+pub fn handoff_receiver<T>(receiver: fdbuf::Receiver<T, Mmap>, socket: &UnixStream) -> Result<(), HandoffError<fdbuf::Receiver<T, Mmap>>> {
+    match handoff(socket, RECEIVER, receiver.handoff(), |id| receiver.set_owner(id)) {
+        Ok(()) => { receiver.detach(); Ok(()) },
+        Err(e) => {
+            if let Err(e2) = receiver.reclaim() { debug!("Failed to reclaim the receiver: {}", e2) };
+            Err(HandoffError { error: e, endpoint: Box::new(receiver) })
+        }
+    }
+}
+
+/// An endpoint received by takeover, not resumed yet.
+struct Takeover {
+    mem: Mmap,
+    signal: OwnedPipe,
+    wait: OwnedPipe,
+    index: usize,
+    generation: usize,
+    sleeping: bool,
+}
+
+fn owned_pipe<I: Iterator<Item=OwnedFd>>(fds: &mut I, split: bool) -> OwnedPipe {
+    let reader = fds.next().unwrap();
+    if split { OwnedPipe::new(reader, fds.next().unwrap()) } else { OwnedPipe::shared(reader) }
+}
+
+/// Receives and checks the endpoint's state and fds.
+fn receive_handoff<T>(socket: &UnixStream, side: u8) -> io::Result<Takeover> {
+    let mut data = [0u8; HANDOFF_LEN];
+    let fds = try!(recv_fds(socket, &mut data, 5));
+    let split = data[26];
+    let nfds = 3 + (split & SIGNAL_SPLIT != 0) as usize + (split & WAIT_SPLIT != 0) as usize;
+    if &data[..8] != HANDOFF || fds.len() != nfds {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "Not an fdringbuf handoff message"));
+    }
+    if data[8] != side {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "The other side handed off the wrong endpoint"));
+    }
+    let (index, generation) = (u64_at(&data, 9), u64_at(&data, 17));
+    let mut fds = fds.into_iter();
+    let mut mem = try!(Mmap::from_sealed_fd(fds.next().unwrap()));
+    try!(::ringbuf::check_resume::<T>(&mut mem, side == SENDER, index, generation));
+    let signal = owned_pipe(&mut fds, split & SIGNAL_SPLIT != 0);
+    let wait = owned_pipe(&mut fds, split & WAIT_SPLIT != 0);
+    Ok(Takeover { mem: mem, signal: signal, wait: wait, index: index, generation: generation, sleeping: data[25] != 0 })
+}
+
+/// The other half of handoff: receives the endpoint, tells the other side who we are, and
+/// waits for the go-ahead. If we fail, or don't get the go-ahead, the other side keeps the endpoint.
+fn takeover<T>(socket: &UnixStream, side: u8) -> io::Result<Takeover> {
+    let t = match receive_handoff::<T>(socket, side) {
+        Ok(t) => t,
+        Err(e) => {
+            let _ = send_fds(socket, &[0u8; READY_LEN], &[]);
+            return Err(e);
+        }
+    };
+    let id = Identity::current();
+    let mut ready = [0u8; READY_LEN];
+    ready[0] = side;
+    ready[1..9].copy_from_slice(&(id.pid as u64).to_ne_bytes());
+    ready[9..17].copy_from_slice(&(id.start as u64).to_ne_bytes());
+    try!(send_fds(socket, &ready, &[]));
+    let mut commit = [0u8; 1];
+    try!(recv_fds(socket, &mut commit, 0));
+    if commit[0] != side { return Err(io::Error::new(io::ErrorKind::InvalidData, "Unexpected handoff message")) };
+    Ok(t)
+}
+
+/// Takes over a sender that another process hands off with handoff_sender,
+/// continuing exactly where it left off.
+pub fn takeover_sender<T: Send + Copy>(socket: &UnixStream) -> io::Result<fdbuf::Sender<T, Mmap>> {
+    let t = try!(takeover::<T>(socket, SENDER));
+    fdbuf::resume_sender(t.mem, t.index, t.generation, t.sleeping, t.signal, t.wait)
+}
+
+/// Takes over a receiver that another process hands off with handoff_receiver.
+pub fn takeover_receiver<T: Send + Copy>(socket: &UnixStream) -> io::Result<fdbuf::Receiver<T, Mmap>> {
+    let t = try!(takeover::<T>(socket, RECEIVER));
+    fdbuf::resume_receiver(t.mem, t.index, t.generation, t.sleeping, t.wait, t.signal)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::net::UnixStream;
+    use super::Builder;
+
+    #[test]
+    fn socket_setup() {
+        let (a, b) = UnixStream::pair().unwrap();
+        let t = ::std::thread::spawn(move || {
+            let mut r = super::connect_receiver::<u64>(&b).unwrap();
+            let mut v = vec![];
+            while v.len() < 20 {
+                r.recv_blocking(|d| { v.extend_from_slice(d); (d.len(), false) }).unwrap();
+            }
+            v
+        });
+        let mut s = Builder::<u64>::new(4).name("test").send_receiver(&a).unwrap();
+        for i in 0..20 { s.send_blocking(|p, _| { unsafe { *p = i }; (1, false) }).unwrap(); }
+        assert_eq!(t.join().unwrap(), (0..20).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn unsealed() {
+        use std::os::unix::io::AsRawFd;
+        use fdbuf::Pipe;
+        let (a, b) = UnixStream::pair().unwrap();
+        let mut m = ::mmap::Mmap::memfd("test", ::channel_bufsize::<u32>(4)).unwrap();
+        ::ringbuf::init::<u32>(&mut m);
+        let (e, f) = (Pipe::eventfd().unwrap(), Pipe::eventfd().unwrap());
+        let data = [&super::HELLO[..], &[super::RECEIVER]].concat();
+        super::send_fds(&a, &data, &[m.fd().unwrap().as_raw_fd(), e.reader, f.reader]).unwrap();
+        let e = super::connect_receiver::<u32>(&b).err().unwrap();
+        assert_eq!(e.kind(), ::std::io::ErrorKind::PermissionDenied);
+    }
+
+    #[test]
+    fn named() {
+        let name = format!("fdringbuf-test-named-{}", unsafe { ::libc::getpid() });
+        let mut s = Builder::<u16>::new(4).mode(0o640).create_sender(&name).unwrap();
+        let mut st: ::libc::stat = unsafe { ::std::mem::zeroed() };
+        let path = ::std::ffi::CString::new(format!("/dev/shm/{}", name)).unwrap();
+        assert_eq!(unsafe { ::libc::stat(path.as_ptr(), &mut st) }, 0);
+        assert_eq!(st.st_mode & 0o777, 0o640);
+
+        assert_eq!(super::open_receiver::<u32>(&name).err().unwrap().kind(), ::std::io::ErrorKind::InvalidData);
+        let mut r = super::open_receiver::<u16>(&name).unwrap();
+        s.send_foreach(3, |i| i as u16 + 1).unwrap();
+        r.recv_blocking(|d| { assert_eq!(d, &[1, 2, 3]); (3, false) }).unwrap();
+
+        drop(s);
+        assert_eq!(super::open_receiver::<u16>(&name).err().unwrap().kind(), ::std::io::ErrorKind::NotFound);
+        assert!(r.recv_blocking(|_| (0, false)).is_err());
+    }
+
+    #[test]
+    fn wrong_type() {
+        let (a, b) = UnixStream::pair().unwrap();
+        let _r = Builder::<u64>::new(4).send_sender(&a).unwrap();
+        let e = super::connect_sender::<u32>(&b).err().unwrap();
+        assert_eq!(e.kind(), ::std::io::ErrorKind::InvalidData);
+    }
+
+    #[cfg(target_os = "linux")]
+    fn wait_exit(pid: ::libc::pid_t) -> i32 {
+        let mut status = 0;
+        assert_eq!(unsafe { ::libc::waitpid(pid, &mut status, 0) }, pid);
+        assert!(::libc::WIFEXITED(status));
+        ::libc::WEXITSTATUS(status)
+    }
+
+    // The test harness runs other tests in other threads, so fork in a process of our own.
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn fork() {
+        let (child, _socket) = ::child::spawn("shm::tests::fork_child");
+        ::child::wait(child);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    #[ignore]
+    fn fork_child() {
+        if ::child::socket().is_none() { return };
+
+        let f = Builder::<u32>::new(4).fork().unwrap();
+        let pid = unsafe { ::libc::fork() };
+        assert!(pid >= 0);
+        if pid == 0 {
+            // The child sums up what it receives, and exits with the sum (or 255 on errors).
+            let mut sum = 0;
+            let code = match f.into_receiver() {
+                Err(_) => 255,
+                Ok(mut r) => loop {
+                    match r.recv_blocking(|d| { sum += d.iter().sum::<u32>(); (d.len(), false) }) {
+                        Ok(_) => {},
+                        Err(ref e) if e.kind() == ::std::io::ErrorKind::BrokenPipe => break sum as i32,
+                        Err(_) => break 255,
+                    }
+                },
+            };
+            unsafe { ::libc::_exit(code) };
+        }
+        let mut s = f.parent_sender(pid).unwrap();
+        for i in 1..11 { s.send_blocking(|p, _| { unsafe { *p = i }; (1, false) }).unwrap(); }
+        drop(s);
+        assert_eq!(wait_exit(pid), 55);
+
+        // A child that dies before attaching is noticed, too.
+        let f = Builder::<u32>::new(4).fork().unwrap();
+        let pid = unsafe { ::libc::fork() };
+        assert!(pid >= 0);
+        if pid == 0 { unsafe { ::libc::_exit(0) } };
+        let mut s = f.parent_sender(pid).unwrap();
+        let e = loop {
+            match s.send_timeout(::std::time::Duration::from_secs(5), |p, _| { unsafe { *p = 1 }; (1, false) }) {
+                Ok(_) => {},
+                Err(e) => break e,
+            }
+        };
+        assert_eq!(e.kind(), ::std::io::ErrorKind::ConnectionAborted);
+        assert_eq!(wait_exit(pid), 0);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn dead_before_attaching() {
+        use std::os::linux::net::SocketAddrExt;
+        use std::os::unix::net::{SocketAddr, UnixListener};
+        // SO_PEERCRED on a socketpair gives the process that created it, so connect for real.
+        let name = format!("fdringbuf-test-dead-before-attaching-{}", unsafe { ::libc::getpid() });
+        let listener = UnixListener::bind_addr(&SocketAddr::from_abstract_name(name.as_bytes()).unwrap()).unwrap();
+        let (child, _) = ::child::spawn("shm::tests::dead_before_attaching_child");
+        let (socket, _) = listener.accept().unwrap();
+        let mut s = Builder::<u32>::new(4).send_receiver(&socket).unwrap();
+        let e = loop {
+            match s.send_timeout(::std::time::Duration::from_secs(5), |p, _| { unsafe { *p = 1 }; (1, false) }) {
+                Ok(_) => {},
+                Err(e) => break e,
+            }
+        };
+        assert_eq!(e.kind(), ::std::io::ErrorKind::ConnectionAborted);
+        ::child::wait(child);
+    }
+
+    // Gets the buffer, but dies without calling connect_receiver.
+    #[cfg(target_os = "linux")]
+    #[test]
+    #[ignore]
+    fn dead_before_attaching_child() {
+        use std::io::Read;
+        use std::os::linux::net::SocketAddrExt;
+        use std::os::unix::net::SocketAddr;
+        if ::child::socket().is_none() { return };
+        let name = format!("fdringbuf-test-dead-before-attaching-{}", unsafe { ::libc::getppid() });
+        let mut socket = UnixStream::connect_addr(&SocketAddr::from_abstract_name(name.as_bytes()).unwrap()).unwrap();
+        socket.read_exact(&mut [0]).unwrap();
+        unsafe { ::libc::_exit(0) };
+    }
+
+    #[test]
+    fn handoff() {
+        let (a, b) = UnixStream::pair().unwrap();
+        let mut s = Builder::<u32>::new(8).send_receiver(&a).unwrap();
+        let mut r = super::connect_receiver::<u32>(&b).unwrap();
+        s.send_foreach(5, |i| i as u32).unwrap();
+        r.recv(|d| { assert_eq!(&d[..2], &[0, 1]); (2, false) }).unwrap();
+
+        // Move the receiver to a "worker" (another thread, here) in the middle of the buffer.
+        let (c, d) = UnixStream::pair().unwrap();
+        let worker = ::std::thread::spawn(move || {
+            let mut r = super::takeover_receiver::<u32>(&d).unwrap();
+            let mut v = vec![];
+            loop {
+                match r.recv_blocking(|d| { v.extend_from_slice(d); (d.len(), false) }) {
+                    Ok(_) => {},
+                    Err(e) => { assert_eq!(e.kind(), ::std::io::ErrorKind::BrokenPipe); return v },
+                }
+            }
+        });
+        super::handoff_receiver(r, &c).unwrap();
+        // The sender is not disturbed.
+        for i in 5..15 { s.send_blocking(|p, _| { unsafe { *p = i }; (1, false) }).unwrap(); }
+        drop(s);
+        assert_eq!(worker.join().unwrap(), (2..15).collect::<Vec<_>>());
+
+        // Failure gives the endpoint back
+        let (a, b) = UnixStream::pair().unwrap();
+        let s = Builder::<u32>::new(8).send_receiver(&a).unwrap();
+        let _r = super::connect_receiver::<u32>(&b).unwrap();
+        let (c, d) = UnixStream::pair().unwrap();
+        drop(d);
+        let mut s = super::handoff_sender(s, &c).unwrap_err().into_inner();
+        s.send_foreach(1, |_| 7).unwrap();
+
+        // So does the other side refusing it
+        let (c, d) = UnixStream::pair().unwrap();
+        let worker = ::std::thread::spawn(move || super::takeover_receiver::<u32>(&d).err().unwrap().kind());
+        let e = super::handoff_sender(s, &c).unwrap_err();
+        assert_eq!(e.error().kind(), ::std::io::ErrorKind::ConnectionRefused);
+        assert_eq!(worker.join().unwrap(), ::std::io::ErrorKind::InvalidInput);
+        let mut s = e.into_inner();
+        s.send_foreach(1, |_| 8).unwrap();
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn handoff_process() {
+        let (a, b) = UnixStream::pair().unwrap();
+        let mut s = Builder::<u32>::new(4).send_receiver(&a).unwrap();
+        let mut r = super::connect_receiver::<u32>(&b).unwrap();
+        s.send_foreach(3, |i| i as u32).unwrap();
+        r.recv(|d| { assert_eq!(d[0], 0); (1, false) }).unwrap();
+
+        let (child, socket) = ::child::spawn("shm::tests::handoff_process_child");
+        super::handoff_receiver(r, &socket).unwrap();
+        for i in 3..20 { s.send_blocking(|p, _| { unsafe { *p = i }; (1, false) }).unwrap(); }
+        drop(s);
+        ::child::wait(child);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    #[ignore]
+    fn handoff_process_child() {
+        let socket = match ::child::socket() { Some(s) => s, None => return };
+        let mut r = super::takeover_receiver::<u32>(&socket).unwrap();
+        let mut v = vec![];
+        loop {
+            match r.recv_blocking(|d| { v.extend_from_slice(d); (d.len(), false) }) {
+                Ok(_) => {},
+                Err(e) => { assert_eq!(e.kind(), ::std::io::ErrorKind::BrokenPipe); break },
+            }
+        }
+        assert_eq!(v, (1..20).collect::<Vec<_>>());
+    }
+}
@@ -0,0 +1,268 @@
+//! Different ways for one side of an fdbuf to wake up the other side.
+//!
+//! `fdbuf::Pipe` implements Signal for fds (eventfds, pipes and socketpairs). This module
+//! has implementations that work without any fds, either with a futex in shared memory,
+//! or in-process only with a Condvar (which can also wake up async tasks).
+//! `Offload` wraps another Signal, so that notifying it makes no syscalls (with `Offload::polling`).
+
+use std::os::unix::io::RawFd;
+use std::io;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicI32, AtomicU32, Ordering};
+use std::task::Waker;
+use std::thread::{JoinHandle, Thread};
+use std::time::Duration;
+
+/// A one-way wakeup channel. It's "notified" by one side of the fdbuf,
+/// and "waited for" and "cleared" by the other side.
+pub trait Signal {
+    /// Wakes up the waiting side.
+    fn notify(&self) -> io::Result<()>;
+
+    /// Waits until notified (without clearing), or until the timeout has passed.
+    /// Returns false on timeout. Spurious wakeups are okay.
+    fn wait(&self, timeout: Option<Duration>) -> io::Result<bool>;
+
+    /// Consumes a notification. Returns false if there was none.
+    fn clear(&self) -> io::Result<bool>;
+
+    /// The fd that becomes readable when notified, if there is one.
+    fn as_raw_fd(&self) -> Option<RawFd> { None }
+
+    /// Wakes the waker (once) when notified, or right away if already notified.
+    /// Returns false if not supported, in which case fdbuf's `poll_wait` has a helper thread
+    /// poll the fd instead (or returns an error if there is none).
+    fn register_waker(&self, _: &Waker) -> bool { false }
+}
+
+/// Signals through a futex word, e g in memory shared between processes.
+#[derive(Debug, Copy, Clone)]
+pub struct Futex(*const AtomicU32);
+
+unsafe impl Send for Futex {}
+
+impl Futex {
+    /// Waits and wakes on a 32 bit word, usually one in a shared buffer header.
+    ///
+    /// # Safety
+    ///
+    /// The word must be aligned, initialized to zero, and stay mapped and valid for as long
+    /// as the Futex (and its clones, which may be sent to other threads) are in use.
+    /// Only Futexes (in this or other processes) may change it.
+    pub unsafe fn new(word: *const AtomicU32) -> Futex { Futex(word) }
+
+    fn word(&self) -> &AtomicU32 { unsafe { &*self.0 } }
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn futex(word: &AtomicU32, op: ::libc::c_int, val: u32, timeout: Option<Duration>) -> io::Result<::libc::c_long> {
+    let ts = timeout.map(|t| ::libc::timespec { tv_sec: t.as_secs() as ::libc::time_t, tv_nsec: t.subsec_nanos() as ::libc::c_long });
+    let tsp = ts.as_ref().map(|t| t as *const _).unwrap_or(::std::ptr::null());
+    // Not FUTEX_PRIVATE_FLAG, as the word might be shared with another process.
+    let e = unsafe { ::libc::syscall(::libc::SYS_futex, word as *const AtomicU32, op, val, tsp, 0usize, 0u32) };
+    if e == -1 { Err(io::Error::last_os_error()) } else { Ok(e) }
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+impl Signal for Futex {
+    fn notify(&self) -> io::Result<()> {
+        if self.word().swap(1, Ordering::SeqCst) == 0 {
+            try!(futex(self.word(), ::libc::FUTEX_WAKE, 1, None));
+        }
+        Ok(())
+    }
+
+    fn wait(&self, timeout: Option<Duration>) -> io::Result<bool> {
+        if self.word().load(Ordering::SeqCst) != 0 { return Ok(true) };
+        // The kernel checks that the word is still zero before sleeping, so we can't miss a wakeup.
+        match futex(self.word(), ::libc::FUTEX_WAIT, 0, timeout) {
+            Ok(_) => Ok(true),
+            Err(e) => match e.raw_os_error() {
+                Some(::libc::EAGAIN) | Some(::libc::EINTR) => Ok(true),
+                Some(::libc::ETIMEDOUT) => Ok(false),
+                _ => Err(e),
+            }
+        }
+    }
+
+    fn clear(&self) -> io::Result<bool> { Ok(self.word().swap(0, Ordering::SeqCst) != 0) }
+}
+
+/// Signals through a Condvar. This only works within a process, but does not need any fds.
+/// It also wakes up async tasks directly, see `register_waker`.
+#[derive(Debug, Clone, Default)]
+pub struct Condvar(Arc<CondvarInner>);
+
+#[derive(Debug, Default)]
+struct CondvarInner {
+    state: Mutex<CondvarState>,
+    cond: ::std::sync::Condvar,
+}
+
+#[derive(Debug, Default)]
+struct CondvarState {
+    notified: bool,
+    waker: Option<Waker>,
+}
+
+impl Condvar {
+    pub fn new() -> Condvar { Default::default() }
+}
+
+impl Signal for Condvar {
+    fn notify(&self) -> io::Result<()> {
+        let waker = {
+            let mut g = self.0.state.lock().unwrap();
+            g.notified = true;
+            g.waker.take()
+        };
+        self.0.cond.notify_one();
+        if let Some(w) = waker { w.wake() };
+        Ok(())
+    }
+
+    fn wait(&self, timeout: Option<Duration>) -> io::Result<bool> {
+        let mut g = self.0.state.lock().unwrap();
+        if g.notified { return Ok(true) };
+        match timeout {
+            None => {
+                while !g.notified { g = self.0.cond.wait(g).unwrap() };
+                Ok(true)
+            }
+            Some(t) => {
+                let (g, r) = self.0.cond.wait_timeout(g, t).unwrap();
+                Ok(g.notified || !r.timed_out())
+            }
+        }
+    }
+
+    fn clear(&self) -> io::Result<bool> {
+        Ok(::std::mem::replace(&mut self.0.state.lock().unwrap().notified, false))
+    }
+
+    fn register_waker(&self, waker: &Waker) -> bool {
+        let mut g = self.0.state.lock().unwrap();
+        if g.notified { waker.wake_by_ref() } else { g.waker = Some(waker.clone()) };
+        true
+    }
+}
+
+/// Hands notifications over to a helper thread, which notifies the wrapped Signal.
+///
+/// This keeps write(2) and friends out of `send` and `recv` on the side using it, e g for a
+/// real-time thread. With `polling`, notifying just sets a flag, which the helper checks
+/// periodically: no syscalls at all, but up to one interval of latency added to every wakeup.
+/// With `parked`, notifying also unparks the helper, which is a futex wake whenever the helper
+/// is sleeping, but wakeups are passed on right away. The waiting functions
+/// (wait, clear and the blocking fdbuf functions) use the wrapped Signal directly.
+///
+/// The helper thread quits when the last clone is dropped, after a final notification if needed.
+pub struct Offload<S: Signal>(Arc<OffloadThread<S>>);
+
+struct OffloadShared<S> {
+    signal: S,
+    pending: AtomicBool,
+    quit: AtomicBool,
+    /// The last error from the helper thread, returned by the next notify.
+    errno: AtomicI32,
+}
+
+struct OffloadThread<S> {
+    shared: Arc<OffloadShared<S>>,
+    /// None if the helper thread polls rather than parks.
+    unpark: Option<Thread>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl<S: Signal + Send + Sync + 'static> Offload<S> {
+    /// The helper thread checks for notifications every `interval`, so notifying makes no syscalls at all.
+    pub fn polling(signal: S, interval: Duration) -> io::Result<Offload<S>> { Offload::spawn(signal, Some(interval)) }
+
+    /// The helper thread sleeps until notified, so notifying makes a syscall (a futex wake)
+    /// if the helper is sleeping.
+    pub fn parked(signal: S) -> io::Result<Offload<S>> { Offload::spawn(signal, None) }
+
+    fn spawn(signal: S, interval: Option<Duration>) -> io::Result<Offload<S>> {
+        let shared = Arc::new(OffloadShared { signal: signal, pending: AtomicBool::new(false),
+            quit: AtomicBool::new(false), errno: AtomicI32::new(0) });
+        let s2 = shared.clone();
+        let thread = try!(::std::thread::Builder::new().name("fdbuf notifier".into()).spawn(move || loop {
+            let quit = s2.quit.load(Ordering::Acquire);
+            if s2.pending.swap(false, Ordering::AcqRel) {
+                if let Err(e) = s2.signal.notify() {
+                    debug!("Offloaded notify failed: {}", e);
+                    s2.errno.store(e.raw_os_error().unwrap_or(::libc::EIO), Ordering::Relaxed);
+                }
+            }
+            if quit { return };
+            match interval {
+                Some(i) => ::std::thread::sleep(i),
+                // If we're unparked between the swap and here, park returns right away.
+                None => ::std::thread::park(),
+            }
+        }));
+        let unpark = if interval.is_none() { Some(thread.thread().clone()) } else { None };
+        Ok(Offload(Arc::new(OffloadThread { shared: shared, unpark: unpark, thread: Some(thread) })))
+    }
+}
+
+impl<S: Signal> Clone for Offload<S> {
+    fn clone(&self) -> Offload<S> { Offload(self.0.clone()) }
+}
+
+impl<S> Drop for OffloadThread<S> {
+    fn drop(&mut self) {
+        self.shared.quit.store(true, Ordering::Release);
+        if let Some(t) = self.thread.take() {
+            t.thread().unpark();
+            let _ = t.join();
+        }
+    }
+}
+
+impl<S: Signal> Signal for Offload<S> {
+    /// Sets a flag and unparks the helper thread; returns an error if the helper thread failed to notify last time.
+    fn notify(&self) -> io::Result<()> {
+        let s = &self.0.shared;
+        if !s.pending.swap(true, Ordering::AcqRel) {
+            if let Some(ref t) = self.0.unpark { t.unpark() };
+        }
+        match s.errno.swap(0, Ordering::Relaxed) {
+            0 => Ok(()),
+            e => Err(io::Error::from_raw_os_error(e)),
+        }
+    }
+
+    fn wait(&self, timeout: Option<Duration>) -> io::Result<bool> { self.0.shared.signal.wait(timeout) }
+
+    fn clear(&self) -> io::Result<bool> { self.0.shared.signal.clear() }
+
+    fn as_raw_fd(&self) -> Option<RawFd> { self.0.shared.signal.as_raw_fd() }
+
+    fn register_waker(&self, waker: &Waker) -> bool { self.0.shared.signal.register_waker(waker) }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Offload;
+    use fdbuf::Pipe;
+    use std::os::unix::io::{OwnedFd, FromRawFd};
+    use std::time::Duration;
+
+    #[test]
+    fn offload() {
+        let (e, f) = (Pipe::eventfd().unwrap(), Pipe::eventfd().unwrap());
+        let _fds = unsafe { (OwnedFd::from_raw_fd(e.reader), OwnedFd::from_raw_fd(f.reader)) };
+        let q = vec![0u8; ::channel_bufsize::<u32>(4)];
+        let (mut s, mut r) = ::fdbuf::channel_with_signal::<u32, _, _>(q,
+            Offload::parked(e).unwrap(), Offload::polling(f, Duration::from_millis(1)).unwrap());
+        let t = ::std::thread::spawn(move || {
+            for i in 0..20 { s.send_blocking(|p, _| { unsafe { *p = i }; (1, false) }).unwrap(); }
+        });
+        let mut v = vec![];
+        while v.len() < 20 { r.recv_blocking(|d| { v.extend_from_slice(d); (d.len(), false) }).unwrap(); }
+        assert_eq!(v, (0..20).collect::<Vec<_>>());
+        t.join().unwrap();
+        assert_eq!(r.recv_blocking(|_| (0, false)).unwrap_err().kind(), ::std::io::ErrorKind::BrokenPipe);
+    }
+}
@@ -0,0 +1,129 @@
+//! System V shared memory, for sharing a buffer with processes that only speak shmget/shmat.
+//!
+//! One side creates the segment and initializes it with `ringbuf::init`, the other side attaches
+//! it by key (or id), and each side attaches its endpoint with `ringbuf::attach_sender` (or
+//! `fdbuf::attach_sender`, if it has fds for signalling) and the receiver counterpart.
+//!
+//! A segment lives on until it is removed, even after every process has detached.
+//! Call `remove_when_attached` once the other side has attached, so that the kernel removes it
+//! when both sides are gone.
+
+use std::io;
+use std::ops::{Deref, DerefMut};
+use fdbuf::cvt;
+
+/// An attached System V shared memory segment, detached on drop.
+pub struct Segment {
+    ptr: *mut u8,
+    len: usize,
+    id: ::libc::c_int,
+}
+
+unsafe impl Send for Segment {}
+
+impl Segment {
+    /// Creates a new segment of len bytes for key, and attaches it.
+    /// Returns an error of kind AlreadyExists if there is a segment for key already.
+    pub fn create(key: ::libc::key_t, len: usize, mode: u32) -> io::Result<Segment> {
+        let id = try!(cvt(unsafe { ::libc::shmget(key, len, ::libc::IPC_CREAT | ::libc::IPC_EXCL | (mode & 0o777) as ::libc::c_int) }));
+        Segment::attach(id)
+    }
+
+    /// Creates a new segment of len bytes which has no key; the other side needs its id.
+    pub fn create_private(len: usize, mode: u32) -> io::Result<Segment> {
+        Segment::create(::libc::IPC_PRIVATE, len, mode)
+    }
+
+    /// Attaches the segment for key.
+    pub fn open(key: ::libc::key_t) -> io::Result<Segment> {
+        let id = try!(cvt(unsafe { ::libc::shmget(key, 0, 0) }));
+        Segment::attach(id)
+    }
+
+    /// Attaches a segment by its id, as returned by `id` (or by shmget in another process).
+    pub fn attach(id: ::libc::c_int) -> io::Result<Segment> {
+        let ds = try!(stat(id));
+        let p = unsafe { ::libc::shmat(id, ::std::ptr::null(), 0) };
+        if p as isize == -1 { return Err(io::Error::last_os_error()) };
+        Ok(Segment { ptr: p as *mut u8, len: ds.shm_segsz as usize, id: id })
+    }
+
+    pub fn id(&self) -> ::libc::c_int { self.id }
+
+    /// The number of processes that have the segment attached (counting every attachment).
+    pub fn attach_count(&self) -> io::Result<usize> {
+        Ok(try!(stat(self.id)).shm_nattch as usize)
+    }
+
+    /// Marks the segment for removal (IPC_RMID); it is removed once every process has detached.
+    /// On Linux, it can still be attached by id until then, but not by key.
+    pub fn remove(&self) -> io::Result<()> {
+        try!(cvt(unsafe { ::libc::shmctl(self.id, ::libc::IPC_RMID, ::std::ptr::null_mut()) }));
+        Ok(())
+    }
+
+    /// Marks the segment for removal if it is attached at least twice, i e, if the other side
+    /// has attached it too. Returns true if it was marked.
+    pub fn remove_when_attached(&self) -> io::Result<bool> {
+        if try!(self.attach_count()) < 2 { return Ok(false) };
+        try!(self.remove());
+        Ok(true)
+    }
+}
+
+fn stat(id: ::libc::c_int) -> io::Result<::libc::shmid_ds> {
+    let mut ds: ::libc::shmid_ds = unsafe { ::std::mem::zeroed() };
+    try!(cvt(unsafe { ::libc::shmctl(id, ::libc::IPC_STAT, &mut ds) }));
+    Ok(ds)
+}
+
+impl Deref for Segment {
+    type Target = [u8];
+    fn deref(&self) -> &[u8] { unsafe { ::std::slice::from_raw_parts(self.ptr, self.len) } }
+}
+
+impl DerefMut for Segment {
+    fn deref_mut(&mut self) -> &mut [u8] { unsafe { ::std::slice::from_raw_parts_mut(self.ptr, self.len) } }
+}
+
+impl Drop for Segment {
+    fn drop(&mut self) {
+        if unsafe { ::libc::shmdt(self.ptr as *const ::libc::c_void) } == -1 {
+            debug!("shmdt failed: {}", io::Error::last_os_error());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Segment;
+    use fdbuf::{self, OwnedPipe, Pipe};
+    use std::os::unix::io::{OwnedFd, FromRawFd};
+
+    #[test]
+    fn segment() {
+        let mut a = Segment::create_private(::channel_bufsize::<u32>(4), 0o600).unwrap();
+        ::ringbuf::init::<u32>(&mut a);
+        let b = Segment::attach(a.id()).unwrap();
+        assert!(a.remove_when_attached().unwrap());
+
+        let empty = unsafe { OwnedFd::from_raw_fd(Pipe::eventfd().unwrap().reader) };
+        let full = unsafe { OwnedFd::from_raw_fd(Pipe::eventfd().unwrap().reader) };
+        let (empty2, full2) = (OwnedPipe::shared(empty.try_clone().unwrap()), OwnedPipe::shared(full.try_clone().unwrap()));
+        let mut s = fdbuf::attach_sender::<u32, _>(a, OwnedPipe::shared(empty), OwnedPipe::shared(full)).unwrap();
+        let mut r = fdbuf::attach_receiver::<u32, _>(b, empty2, full2).unwrap();
+        s.send_foreach(3, |i| i as u32 * 5).unwrap();
+        r.recv_blocking(|d| { assert_eq!(d, &[0, 5, 10]); (3, false) }).unwrap();
+        drop(s);
+        assert!(r.recv(|d| (d.len(), false)).is_err());
+    }
+
+    #[test]
+    fn in_process() {
+        let m = Segment::create_private(::channel_bufsize::<u8>(8), 0o600).unwrap();
+        m.remove().unwrap();
+        let (mut s, mut r) = ::ringbuf::channel::<u8, _>(m);
+        s.send_foreach(2, |i| i as u8 + 1).unwrap();
+        r.recv(|d| { assert_eq!(d, &[1, 2]); 2 }).unwrap();
+    }
+}
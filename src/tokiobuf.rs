@@ -0,0 +1,249 @@
+//! Async fdbuf endpoints for tokio.
+//!
+//! These wrap an fdbuf Sender or Receiver and register its wait fd (and the peer's pidfd,
+//! if the peer is another process) with tokio's reactor, so they only work with Signals
+//! that have an fd, such as `fdbuf::Pipe`.
+
+use std::future::Future;
+use std::io;
+use std::os::unix::io::RawFd;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use tokio::io::unix::AsyncFd;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use fdbuf::{self, Pipe};
+use signal::Signal;
+
+/// Registers the wait fd and the peer's pidfd. Must be called from within a tokio runtime.
+fn register(wait: Option<RawFd>, peer: Option<RawFd>) -> io::Result<(AsyncFd<RawFd>, Option<AsyncFd<RawFd>>)> {
+    let wait = try!(wait.ok_or(io::Error::new(io::ErrorKind::InvalidInput, "The Signal has no fd to wait for")));
+    // The fds are owned by the fdbuf endpoint, which outlives the AsyncFds. The peer's pidfd
+    // stays open until reopen_peer_fd, which is only called after its AsyncFd is dropped.
+    let wait = try!(unsafe { AsyncFd::register(wait) });
+    Ok((wait, try!(register_peer(peer))))
+}
+
+fn register_peer(peer: Option<RawFd>) -> io::Result<Option<AsyncFd<RawFd>>> {
+    match peer { Some(p) => Ok(Some(try!(unsafe { AsyncFd::register(p) }))), None => Ok(None) }
+}
+
+/// Returns Ready if the wait fd (or the peer's pidfd) is readable, otherwise registers the waker.
+fn poll_fds(wait: &AsyncFd<RawFd>, peer: &Option<AsyncFd<RawFd>>, cx: &mut Context) -> Poll<io::Result<()>> {
+    match wait.poll_read_ready(cx) {
+        Poll::Ready(Ok(mut g)) => { g.clear_ready(); return Poll::Ready(Ok(())) },
+        Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+        Poll::Pending => {},
+    }
+    // The pidfd stays readable once the peer has died, so it is never cleared.
+    match *peer {
+        Some(ref p) => p.poll_read_ready(cx).map_ok(|_| ()),
+        None => Poll::Pending,
+    }
+}
+
+macro_rules! try_ready {
+    ($e: expr) => { match $e {
+        Poll::Ready(Ok(r)) => r,
+        Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+        Poll::Pending => return Poll::Pending,
+    } }
+}
+
+macro_rules! try_poll {
+    ($e: expr) => { match $e { Ok(r) => r, Err(e) => return Poll::Ready(Err(From::from(e))) } }
+}
+
+macro_rules! refresh_peer {
+    ($s: expr) => { if $s.inner.peer_changed() {
+        // The other side has been handed off: deregister the old pidfd before it's closed.
+        $s.peer = None;
+        $s.peer = try_poll!($s.inner.reopen_peer_fd().and_then(register_peer));
+    } }
+}
+
+/// The sending side of an fdbuf, for use with tokio.
+pub struct AsyncSender<T, U, S: Signal = Pipe> {
+    // These must be dropped (deregistered) before the fds are closed.
+    fd: AsyncFd<RawFd>,
+    peer: Option<AsyncFd<RawFd>>,
+    inner: fdbuf::Sender<T, U, S>,
+}
+
+/// The receiving side of an fdbuf, for use with tokio.
+pub struct AsyncReceiver<T, U, S: Signal = Pipe> {
+    fd: AsyncFd<RawFd>,
+    peer: Option<AsyncFd<RawFd>>,
+    inner: fdbuf::Receiver<T, U, S>,
+}
+
+impl<T, U, S: Signal> AsyncSender<T, U, S> {
+    /// Must be called from within a tokio runtime.
+    pub fn new(mut inner: fdbuf::Sender<T, U, S>) -> io::Result<AsyncSender<T, U, S>> {
+        let peer = try!(inner.peer_fd());
+        let (fd, peer) = try!(register(inner.wait_fd(), peer));
+        Ok(AsyncSender { fd: fd, peer: peer, inner: inner })
+    }
+
+    /// Returns the number of items that can be written, or Pending if the buffer is full.
+    pub fn poll_ready(&mut self, cx: &mut Context) -> Poll<io::Result<usize>> {
+        loop {
+            let n = try_poll!(self.inner.wait_ready());
+            if n > 0 { return Poll::Ready(Ok(n)) };
+            refresh_peer!(self);
+            try_ready!(poll_fds(&self.fd, &self.peer, cx));
+        }
+    }
+
+    /// Waits until there is room for at least one item, and returns the number of items
+    /// that can be written.
+    pub fn reserve<'a>(&'a mut self) -> impl Future<Output = io::Result<usize>> + 'a {
+        ::std::future::poll_fn(move |cx| self.poll_ready(cx))
+    }
+
+    /// Waits until there is room for at least one item, then calls fdbuf::Sender::send.
+    pub fn send<'a, F: FnMut(*mut T, usize) -> (usize, bool) + 'a>(&'a mut self, mut f: F) -> impl Future<Output = io::Result<usize>> + 'a {
+        ::std::future::poll_fn(move |cx| {
+            try_ready!(self.poll_ready(cx));
+            Poll::Ready(self.inner.send(&mut f))
+        })
+    }
+
+    pub fn get_ref(&self) -> &fdbuf::Sender<T, U, S> { &self.inner }
+
+    /// Gets the fdbuf::Sender back.
+    pub fn into_inner(self) -> fdbuf::Sender<T, U, S> { self.inner }
+}
+
+impl<T, U, S: Signal> AsyncReceiver<T, U, S> {
+    /// Must be called from within a tokio runtime.
+    pub fn new(mut inner: fdbuf::Receiver<T, U, S>) -> io::Result<AsyncReceiver<T, U, S>> {
+        let peer = try!(inner.peer_fd());
+        let (fd, peer) = try!(register(inner.wait_fd(), peer));
+        Ok(AsyncReceiver { fd: fd, peer: peer, inner: inner })
+    }
+
+    /// Returns the number of items that can be read, or Pending if the buffer is empty.
+    /// If the sender has been closed, this returns at least one, and the next recv will tell.
+    pub fn poll_ready(&mut self, cx: &mut Context) -> Poll<io::Result<usize>> {
+        loop {
+            let n = try_poll!(self.inner.wait_ready());
+            if n > 0 { return Poll::Ready(Ok(n)) };
+            refresh_peer!(self);
+            try_ready!(poll_fds(&self.fd, &self.peer, cx));
+        }
+    }
+
+    /// Waits until there is at least one item to read, then calls fdbuf::Receiver::recv.
+    pub fn recv<'a, F: FnMut(&[T]) -> (usize, bool) + 'a>(&'a mut self, mut f: F) -> impl Future<Output = io::Result<usize>> + 'a {
+        ::std::future::poll_fn(move |cx| {
+            try_ready!(self.poll_ready(cx));
+            Poll::Ready(self.inner.recv(&mut f))
+        })
+    }
+
+    pub fn get_ref(&self) -> &fdbuf::Receiver<T, U, S> { &self.inner }
+
+    /// Gets the fdbuf::Receiver back.
+    pub fn into_inner(self) -> fdbuf::Receiver<T, U, S> { self.inner }
+}
+
+impl<U, S: Signal + Unpin> AsyncWrite for AsyncSender<u8, U, S> {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let s = self.get_mut();
+        try_ready!(s.poll_ready(cx));
+        let mut written = 0;
+        try_poll!(s.inner.send(|p, count| {
+            let n = ::std::cmp::min(count, buf.len() - written);
+            unsafe { ::std::ptr::copy_nonoverlapping(buf[written..].as_ptr(), p, n) };
+            written += n;
+            (n, written < buf.len())
+        }));
+        Poll::Ready(Ok(written))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _: &mut Context) -> Poll<io::Result<()>> { Poll::Ready(Ok(())) }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _: &mut Context) -> Poll<io::Result<()>> {
+        Poll::Ready(self.get_mut().inner.close())
+    }
+}
+
+impl<U, S: Signal + Unpin> AsyncRead for AsyncReceiver<u8, U, S> {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context, buf: &mut ReadBuf) -> Poll<io::Result<()>> {
+        let r = self.get_mut();
+        try_ready!(r.poll_ready(cx));
+        let res = r.inner.recv(|d| {
+            let n = ::std::cmp::min(d.len(), buf.remaining());
+            buf.put_slice(&d[..n]);
+            (n, buf.remaining() > 0)
+        });
+        match res {
+            // End of file
+            Err(ref e) if e.kind() == io::ErrorKind::BrokenPipe => Poll::Ready(Ok(())),
+            Err(e) => Poll::Ready(Err(e)),
+            Ok(_) => Poll::Ready(Ok(())),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{AsyncSender, AsyncReceiver};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use std::thread;
+
+    fn runtime() -> ::tokio::runtime::Runtime {
+        ::tokio::runtime::Builder::new_current_thread().enable_io().build().unwrap()
+    }
+
+    #[test]
+    fn send_recv() {
+        let q = vec![0u8; ::channel_bufsize::<u32>(4)];
+        let (s, r) = ::fdbuf::channel_new::<u32, _>(q).unwrap();
+        let sender = thread::spawn(move || {
+            let rt = runtime();
+            let _g = rt.enter();
+            let mut s = AsyncSender::new(s).unwrap();
+            for i in 0..10u32 {
+                rt.block_on(s.send(|p, _| { unsafe { *p = i }; (1, false) })).unwrap();
+            }
+            assert!(rt.block_on(s.reserve()).unwrap() > 0);
+        });
+
+        let rt = runtime();
+        let _g = rt.enter();
+        let mut r = AsyncReceiver::new(r).unwrap();
+        let mut v = vec![];
+        while v.len() < 10 {
+            rt.block_on(r.recv(|d| { v.extend_from_slice(d); (d.len(), false) })).unwrap();
+        }
+        assert_eq!(v, (0..10).collect::<Vec<_>>());
+        sender.join().unwrap();
+        // The sender is dropped now
+        let e = rt.block_on(r.recv(|d| (d.len(), false))).unwrap_err();
+        assert_eq!(e.kind(), ::std::io::ErrorKind::BrokenPipe);
+    }
+
+    #[test]
+    fn read_write() {
+        let q = vec![0u8; ::channel_bufsize::<u8>(5)];
+        let (s, r) = ::fdbuf::channel_new::<u8, _>(q).unwrap();
+        let data: Vec<u8> = (0..100).collect();
+        let d2 = data.clone();
+        let writer = thread::spawn(move || {
+            let rt = runtime();
+            let _g = rt.enter();
+            let mut s = AsyncSender::new(s).unwrap();
+            rt.block_on(s.write_all(&d2)).unwrap();
+            rt.block_on(s.shutdown()).unwrap();
+        });
+
+        let rt = runtime();
+        let _g = rt.enter();
+        let mut r = AsyncReceiver::new(r).unwrap();
+        let mut v = vec![];
+        rt.block_on(r.read_to_end(&mut v)).unwrap();
+        assert_eq!(v, data);
+        writer.join().unwrap();
+    }
+}
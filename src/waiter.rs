@@ -0,0 +1,101 @@
+//! Wakes up tasks waiting in fdbuf's `poll_wait` on fd Signals, for executors that have no
+//! reactor of their own (or one we don't know about).
+//!
+//! A helper thread, started on first use (and again in a forked child), polls the wait fd
+//! and the peer's pidfd of every waiting endpoint, and wakes up the task when either becomes
+//! readable. Registrations are one-shot, just like wakers are.
+
+use std::collections::HashMap;
+use std::io;
+use std::os::unix::io::RawFd;
+use std::sync::{Arc, Mutex};
+use std::task::Waker;
+use fdbuf::{OwnedPipe, Pipe};
+use signal::Signal;
+
+struct Entry {
+    peer: Option<RawFd>,
+    waker: Waker,
+}
+
+struct Waiter {
+    /// Keyed by the wait fd.
+    entries: Mutex<HashMap<RawFd, Entry>>,
+    /// Written to when the entries change, so that the helper thread polls the new set.
+    wakeup: OwnedPipe,
+    pid: ::libc::pid_t,
+}
+
+static WAITER: Mutex<Option<Arc<Waiter>>> = Mutex::new(None);
+
+fn get() -> io::Result<Arc<Waiter>> {
+    let mut g = WAITER.lock().unwrap();
+    let pid = unsafe { ::libc::getpid() };
+    if let Some(ref w) = *g {
+        // After a fork, the helper thread is gone.
+        if w.pid == pid { return Ok(w.clone()) };
+    }
+    let wakeup = unsafe { OwnedPipe::from_pipe(try!(Pipe::anonymous())) };
+    let w = Arc::new(Waiter { entries: Mutex::new(HashMap::new()), wakeup: wakeup, pid: pid });
+    let w2 = w.clone();
+    try!(::std::thread::Builder::new().name("fdbuf waiter".into()).spawn(move || w2.run()));
+    *g = Some(w.clone());
+    Ok(w)
+}
+
+/// Wakes up the task once `wait` (or `peer`) becomes readable. Replaces any earlier
+/// registration for the same wait fd.
+pub(crate) fn register(wait: RawFd, peer: Option<RawFd>, waker: &Waker) -> io::Result<()> {
+    let w = try!(get());
+    {
+        let mut entries = w.entries.lock().unwrap();
+        if let Some(e) = entries.get(&wait) {
+            // Already being polled for, so there's no need to wake up the helper thread.
+            if e.peer == peer && e.waker.will_wake(waker) { return Ok(()) };
+        }
+        entries.insert(wait, Entry { peer: peer, waker: waker.clone() });
+    }
+    w.wakeup.as_pipe().notify()
+}
+
+/// Forgets about the wait fd, e g because it's about to be closed.
+pub(crate) fn deregister(wait: RawFd) {
+    let w = match *WAITER.lock().unwrap() { Some(ref w) => w.clone(), None => return };
+    if w.entries.lock().unwrap().remove(&wait).is_none() { return };
+    if let Err(e) = w.wakeup.as_pipe().notify() { debug!("Failed to wake up the waiter thread: {}", e) };
+}
+
+impl Waiter {
+    fn run(&self) {
+        let wakeup = self.wakeup.as_pipe();
+        loop {
+            // The first fd is the wakeup pipe; the others are listed in keys by their wait fd.
+            let mut fds = vec![::libc::pollfd { fd: wakeup.reader, events: ::libc::POLLIN, revents: 0 }];
+            let mut keys = vec![];
+            for (&wait, e) in self.entries.lock().unwrap().iter() {
+                for &fd in Some(wait).iter().chain(e.peer.iter()) {
+                    fds.push(::libc::pollfd { fd: fd, events: ::libc::POLLIN, revents: 0 });
+                    keys.push(wait);
+                }
+            }
+            let e = unsafe { ::libc::poll(fds.as_mut_ptr(), fds.len() as ::libc::nfds_t, -1) };
+            if e == -1 {
+                let err = io::Error::last_os_error();
+                if err.kind() == io::ErrorKind::Interrupted { continue };
+                debug!("The waiter thread failed to poll: {}", err);
+                ::std::thread::sleep(::std::time::Duration::from_millis(10));
+                continue;
+            }
+            if fds[0].revents != 0 {
+                if let Err(e) = wakeup.clear() { debug!("Failed to clear the waiter thread's wakeup: {}", e) };
+            }
+            // Closed fds (POLLNVAL) count as ready too, so that they're dropped.
+            let woken: Vec<Waker> = {
+                let mut entries = self.entries.lock().unwrap();
+                fds[1..].iter().zip(keys.iter()).filter(|&(p, _)| p.revents != 0)
+                    .filter_map(|(_, k)| entries.remove(k)).map(|e| e.waker).collect()
+            };
+            for w in woken { w.wake() };
+        }
+    }
+}